@@ -33,7 +33,7 @@ fn assa_to_dialogue(pair: Pair<Rule>, mut list: Vec<Dialogue>) -> Vec<Dialogue>
         let _effect = inner.next().unwrap();
         let text = inner.next().unwrap();
         let string = text.as_str().to_string();
-        let dialogue = Dialogue { start, end, text: string };
+        let dialogue = Dialogue { start, end, text: string, image: None };
         list.push(dialogue);
       }
       _ => {
@@ -47,14 +47,65 @@ fn assa_to_dialogue(pair: Pair<Rule>, mut list: Vec<Dialogue>) -> Vec<Dialogue>
 impl From<Pair<'_, Rule>> for Time {
   fn from(value: Pair<Rule>) -> Self {
     let mut time = value.into_inner();
-    let hour: u8 = time.next().unwrap().as_str().parse().unwrap();
-    let min: u8 = time.next().unwrap().as_str().parse().unwrap();
-    let sec: u8 = time.next().unwrap().as_str().parse().unwrap();
-    let hun: u16 = time.next().unwrap().as_str().parse().unwrap();
-    Time { hour, min, sec, mil: hun * 10 }
+    let hour: u32 = time.next().unwrap().as_str().parse().unwrap();
+    let min: u32 = time.next().unwrap().as_str().parse().unwrap();
+    let sec: u32 = time.next().unwrap().as_str().parse().unwrap();
+    let hun: u32 = time.next().unwrap().as_str().parse().unwrap();
+    Time::new(hour, min, sec, hun * 10)
   }
 }
 
+const SCRIPT_INFO: &str = "[Script Info]
+ScriptType: v4.00+
+PlayResX: 640
+PlayResY: 360
+WrapStyle: 0
+ScaledBorderAndShadow: yes
+
+[V4+ Styles]
+Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
+Style: Default,Arial,24,&H00FFFFFF,&H0000FFFF,&H00000000,&H80000000,0,0,0,0,100,100,0,0,1,2,1,2,20,20,20,1
+";
+
+/// Top aligned and yellow, for the translation in bilingual subtitles.
+const TARGET_STYLE: &str = "Style: Target,Arial,20,&H0000FFFF,&H0000FFFF,&H00000000,&H80000000,0,0,0,0,100,100,0,0,1,2,1,8,20,20,20,1
+";
+
+const EVENTS: &str = "
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+";
+
+pub fn dialogue_to_assa(dialogue: &[Dialogue]) -> String {
+  let events: String = dialogue.iter()
+    .filter(|d| !d.text.is_empty())
+    .map(|d| event("Default", d, &d.text))
+    .collect();
+  format!("{}{}{}", SCRIPT_INFO, EVENTS, events)
+}
+
+/// Source text on the bottom and its translation on top, both with the source timing.
+pub fn bilingual_to_assa(pairs: &[(&Dialogue, String)]) -> String {
+  let mut events = String::new();
+  for (d, translation) in pairs.iter().filter(|(d, _)| !d.text.is_empty()) {
+    events.push_str(&event("Default", d, &d.text));
+    if !translation.is_empty() {
+      events.push_str(&event("Target", d, translation));
+    }
+  }
+  format!("{}{}{}{}", SCRIPT_INFO, TARGET_STYLE, EVENTS, events)
+}
+
+fn event(style: &str, dialogue: &Dialogue, text: &str) -> String {
+  format!("Dialogue: 0,{},{},{},,0,0,0,,{}\n", timestamp(&dialogue.start), timestamp(&dialogue.end), style,
+          text.replace("<br/>", "\\N").replace('\n', "\\N"))
+}
+
+fn timestamp(time: &Time) -> String {
+  let (hour, min, sec, mil) = time.parts();
+  format!("{}:{:02}:{:02}.{:02}", hour, min, sec, mil / 10)
+}
+
 #[allow(dead_code)]
 fn dump_rules(level: usize, pair: Pair<Rule>) {
   for pair in pair.into_inner() {
@@ -111,10 +162,10 @@ mod tests {
 
   #[test]
   fn it_matches_secondary_subtitle() {
-    let primary = offset_subtitle_file("tests/ichigo-01_jp.ass").unwrap();
+    let primary = offset_subtitle_file("tests/ichigo-01_jp.ass", &None).unwrap();
     assert_eq!(350, primary.len());
     let first = primary.first().unwrap();
-    let secondary = offset_subtitle_file("tests/ichigo-01_en.ass").unwrap();
+    let secondary = offset_subtitle_file("tests/ichigo-01_en.ass", &None).unwrap();
     assert_eq!(360, secondary.len());
     let second = find_secondary_matches(first, &secondary);
     assert_matches!(second.first(), Some(Dialogue {text, .. }) if text == "What lovely weather.");
@@ -126,9 +177,9 @@ mod tests {
 
   #[test]
   fn it_matches_multiple_lines() {
-    let primary = offset_subtitle_file("tests/ichigo-01_jp.ass").unwrap();
+    let primary = offset_subtitle_file("tests/ichigo-01_jp.ass", &None).unwrap();
     let first = primary.get(4).unwrap();
-    let secondary = offset_subtitle_file("tests/ichigo-01_en.ass").unwrap();
+    let secondary = offset_subtitle_file("tests/ichigo-01_en.ass", &None).unwrap();
     let second = find_secondary_matches(first, &secondary);
 
     assert_eq!(2, second.len());
@@ -138,8 +189,8 @@ mod tests {
 
   #[test]
   fn it_generates_tab_separated() {
-    let primary = offset_subtitle_file("tests/ichigo-01_jp.ass").unwrap();
-    let secondary = offset_subtitle_file("tests/ichigo-01_en.ass").unwrap();
+    let primary = offset_subtitle_file("tests/ichigo-01_jp.ass", &None).unwrap();
+    let secondary = offset_subtitle_file("tests/ichigo-01_en.ass", &None).unwrap();
     for first in primary.iter() {
       let second = find_secondary_matches(first, &secondary);
       let text = first.text
@@ -151,4 +202,25 @@ mod tests {
       println!("{}\t{}", text, second);
     }
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn it_writes_substation() {
+    let contents = fs::read_to_string("tests/ichigo-01_jp.ass").unwrap();
+    let dialogue = parse_assa_to_dialogue(&contents).unwrap();
+    let written = dialogue_to_assa(&dialogue);
+    assert!(written.contains("Dialogue: 0,0:01:39.62,0:01:41.62,Default,,0,0,0,,いーい天気\n"));
+    assert_eq!(dialogue, parse_assa_to_dialogue(&written).unwrap());
+  }
+
+  #[test]
+  fn it_writes_bilingual_substation() {
+    let primary = offset_subtitle_file("tests/ichigo-01_jp.ass", &None).unwrap();
+    let first = primary.first().unwrap();
+    let pairs = vec![(first, "What lovely weather.".to_string())];
+    let written = bilingual_to_assa(&pairs);
+    assert!(written.contains("Style: Target,"));
+    assert!(written.contains("Dialogue: 0,0:01:39.62,0:01:41.62,Default,,0,0,0,,いーい天気\n"));
+    assert!(written.contains("Dialogue: 0,0:01:39.62,0:01:41.62,Target,,0,0,0,,What lovely weather.\n"));
+    assert_eq!(2, parse_assa_to_dialogue(&written).unwrap().len());
+  }
+}
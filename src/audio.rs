@@ -0,0 +1,706 @@
+use std::fs;
+
+use clap::ValueEnum;
+use ffmpeg_next::{ChannelLayout, Dictionary, Error, filter, format, Packet, Rational, Stream};
+use ffmpeg_next::codec::{self, context, decoder, encoder};
+use ffmpeg_next::format::{sample, Sample};
+use ffmpeg_next::format::context::{Input, Output};
+use ffmpeg_next::format::input;
+use ffmpeg_next::media::Type;
+use ffmpeg_next::util::frame::audio::Audio;
+use log::{debug, info, warn};
+
+use crate::{Dialogue, Result, sample_range, Time};
+use crate::loudness::{LoudnessMeter, METER_RATE};
+use crate::mp3::{AudioPadding, AudioSuffix};
+use crate::plot::PlotOptions;
+use crate::tags::{ClipTags, TagSource};
+use crate::vocals::{isolate, noise_gaps, noise_profile, VocalIsolation};
+
+/// Encoded audio is resampled to this rate and timed in samples.
+const AUDIO_RATE: i32 = 48000;
+
+/// The limiter runs at four times the rate, so it also catches the peaks between samples.
+const OVERSAMPLING: i32 = 4;
+
+/// Samples in each frame of processed audio passed back to the filters.
+const SAMPLES_FRAME: usize = 4096;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum AudioFormat {
+  #[default]
+  Mp3,
+  /// Opus in Ogg
+  Opus,
+  /// Vorbis in Ogg
+  Vorbis,
+  /// AAC in MP4
+  Aac,
+  /// Uncompressed 16 bit
+  Wav,
+}
+
+impl AudioFormat {
+  pub fn extension(&self) -> &'static str {
+    match self {
+      AudioFormat::Mp3 => "mp3",
+      AudioFormat::Opus => "opus",
+      AudioFormat::Vorbis => "ogg",
+      AudioFormat::Aac => "m4a",
+      AudioFormat::Wav => "wav",
+    }
+  }
+
+  fn codec(&self) -> &'static str {
+    match self {
+      AudioFormat::Mp3 => "libmp3lame",
+      AudioFormat::Opus => "libopus",
+      AudioFormat::Vorbis => "libvorbis",
+      AudioFormat::Aac => "aac",
+      AudioFormat::Wav => "pcm_s16le",
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AudioEncoder {
+  pub format: AudioFormat,
+  /// Bitrate in kbit/s for the compressed formats
+  pub bitrate: usize,
+  /// Downmix to one channel
+  pub mono: bool,
+}
+
+impl Default for AudioEncoder {
+  fn default() -> Self {
+    AudioEncoder { format: AudioFormat::Mp3, bitrate: 128, mono: false }
+  }
+}
+
+impl AudioEncoder {
+  fn layout(&self) -> (ChannelLayout, &'static str) {
+    match self.mono {
+      true => (ChannelLayout::MONO, "mono"),
+      false => (ChannelLayout::STEREO, "stereo"),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum LoudnessScope {
+  /// Each clip to the target on its own
+  Clip,
+  /// All clips by the same gain, from the loudness of all the dialogue
+  Episode,
+}
+
+/// Loudness normalization as in EBU R128.
+#[derive(Clone, Copy, Debug)]
+pub struct Normalization {
+  pub scope: LoudnessScope,
+  /// Integrated loudness in LUFS
+  pub target: f64,
+  /// True peaks, between the samples too, are limited to this level in dBFS
+  pub peak: f64,
+}
+
+impl Default for Normalization {
+  fn default() -> Self {
+    Normalization { scope: LoudnessScope::Clip, target: -16.0, peak: -1.5 }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct AudioOptions<'a> {
+  /// Audio stream index or language code, instead of the default stream
+  pub stream: Option<String>,
+  pub encoder: AudioEncoder,
+  pub padding: AudioPadding,
+  /// Fade in and out at the edges of each clip, which avoids clicks
+  pub fade: Time,
+  pub normalization: Option<Normalization>,
+  /// Also encodes each clip slowed down to this speed, keeping its pitch
+  pub slow: Option<f64>,
+  pub tags: TagSource<'a>,
+  pub isolation: Option<VocalIsolation>,
+  pub plot: Option<PlotOptions>,
+}
+
+impl Default for AudioOptions<'_> {
+  fn default() -> Self {
+    AudioOptions {
+      stream: None,
+      encoder: AudioEncoder::default(),
+      padding: AudioPadding::default(),
+      fade: Time::from_millis(10),
+      normalization: None,
+      slow: None,
+      tags: TagSource::default(),
+      isolation: None,
+      plot: None,
+    }
+  }
+}
+
+/// Decodes the audio of a video or audio file and encodes a clip of each dialogue, named and tagged
+/// like the clips cut from a pre-made MP3.
+pub fn extract_audio_clips(media_file: &str, folder: &str, prefix: &str, subtitles: &[Dialogue], suffix: AudioSuffix,
+                           sample: &Option<u32>, options: &AudioOptions) -> Result<()> {
+  ffmpeg_next::init()?;
+  info!("Extracting audio clips from {}", media_file);
+
+  let (start, end) = sample_range(sample);
+  let mut input = input(&media_file)?;
+  let stream = select_stream(&input, options.stream.as_deref()).ok_or(Error::StreamNotFound)?;
+  let mut source = AudioSource::open(&stream)?;
+  let length = media_length(&input, &stream);
+  let buffer = source.buffer();
+  let clips = subtitles.iter().enumerate()
+    .filter(|(_, d)| d.start >= start && d.start <= end)
+    .map(|(index, dialogue)| (index, dialogue, options.padding.range(subtitles, index)))
+    .filter(|(index, dialogue, (from, _))| {
+      let past = length.is_some_and(|length| *from >= length);
+      if past {
+        warn!("Skipping dialogue {} at {}, which starts after the end of the audio: {}", index + 1, dialogue.start,
+              dialogue.text);
+      }
+      !past
+    })
+    .collect::<Vec<_>>();
+
+  let episode = match options.normalization {
+    Some(Normalization { scope: LoudnessScope::Episode, .. }) => {
+      let mut meter = LoudnessMeter::default();
+      for (_, _, (from, to)) in clips.iter() {
+        meter.add(&source.read(&mut input, *from, *to, METER_RATE as i32)?);
+      }
+      let loudness = meter.integrated();
+      info!("Dialogue loudness {}", lufs(loudness));
+      loudness
+    }
+    _ => None,
+  };
+  for (index, dialogue, (from, to)) in clips {
+    let filename = suffix.filename(folder, prefix, dialogue, options.encoder.format.extension());
+    let isolated = match options.isolation {
+      Some(isolation) => Some(source.isolate(&mut input, subtitles, index, from, to, &isolation)?),
+      None => None,
+    };
+    if let Some(plot) = options.plot {
+      let filename = format!("{}/{}", folder, suffix.plot_name(prefix, dialogue));
+      debug!("Plotting {}", filename);
+      match &isolated {
+        Some(samples) => plot.save(&filename, samples, AUDIO_RATE as u32)?,
+        None => plot.save(&filename, &source.read(&mut input, from, to, AUDIO_RATE)?, AUDIO_RATE as u32)?,
+      }
+    }
+    let effects = match options.normalization {
+      None => {
+        debug!("Saving {}", filename);
+        fades(to - from, options.fade)
+      }
+      Some(normalization) => {
+        let loudness = match normalization.scope {
+          LoudnessScope::Clip => {
+            let mut meter = LoudnessMeter::default();
+            match &isolated {
+              // Isolated audio is at the rate of the meter already
+              Some(samples) => meter.add(samples),
+              None => meter.add(&source.read(&mut input, from, to, METER_RATE as i32)?),
+            }
+            meter.integrated()
+          }
+          LoudnessScope::Episode => episode,
+        };
+        info!("Saving {} at {}", filename, lufs(loudness));
+        normalize(loudness, &normalization) + &fades(to - from, options.fade)
+      }
+    };
+    let tags = options.tags.tags(folder, prefix, subtitles, index);
+    let mut encode = |filename: &str, effects: &str| match &isolated {
+      Some(samples) => write_clip(filename, &tags, options.encoder.format,
+        |output| AudioWriter::create(output, &samples_buffer(), Time::default(), to - from, effects, &options.encoder),
+        |writer, output| writer.add_samples(samples, output)),
+      None => write_clip(filename, &tags, options.encoder.format,
+        |output| AudioWriter::create(output, &buffer, from, to, effects, &options.encoder),
+        |writer, output| source.feed(&mut input, from, to, writer, output)),
+    };
+    encode(&filename, &effects)?;
+    if let Some(speed) = options.slow {
+      let filename = format!("{}/{}", folder, suffix.slow_name(prefix, dialogue, options.encoder.format.extension()));
+      debug!("Saving {}", filename);
+      encode(&filename, &(effects + &stretch(speed)))?;
+    }
+  }
+  info!("Done!");
+  Ok(())
+}
+
+/// Selects an audio stream by its container index or by language code, or the default one.
+pub(crate) fn select_stream<'a>(input: &'a Input, selector: Option<&str>) -> Option<Stream<'a>> {
+  let Some(selector) = selector else { return input.streams().best(Type::Audio); };
+  let index = selector.parse::<usize>().ok();
+  input.streams()
+    .filter(|stream| stream.parameters().medium() == Type::Audio)
+    .find(|stream| Some(stream.index()) == index || stream.metadata().get("language") == Some(selector))
+}
+
+/// Length of the stream, or of the whole file when the container only records that.
+fn media_length(input: &Input, stream: &Stream) -> Option<Time> {
+  match stream.duration() {
+    duration if duration > 0 => Some(Time::from_timestamp(duration, stream.time_base())),
+    _ => (input.duration() > 0).then(|| Time::from_micros(input.duration())),
+  }
+}
+
+pub(crate) struct AudioSource {
+  pub decoder: decoder::Audio,
+  pub index: usize,
+  pub time_base: Rational,
+}
+
+impl AudioSource {
+  pub fn open(stream: &Stream) -> Result<AudioSource> {
+    Ok(AudioSource {
+      decoder: context::Context::from_parameters(stream.parameters())?.decoder().audio()?,
+      index: stream.index(),
+      time_base: stream.time_base(),
+    })
+  }
+
+  /// Arguments of the `abuffer` filter for the decoded audio.
+  pub fn buffer(&self) -> String {
+    let decoder = &self.decoder;
+    let layout = match decoder.channel_layout() {
+      layout if layout.bits() == 0 => ChannelLayout::default(decoder.channels() as i32),
+      layout => layout,
+    };
+    format!(
+      "time_base={}/{}:sample_rate={}:sample_fmt={}:channel_layout=0x{:x}",
+      self.time_base.numerator(),
+      self.time_base.denominator(),
+      decoder.rate(),
+      decoder.format().name(),
+      layout.bits(),
+    )
+  }
+
+  /// Seeks back to `from` and adds the audio until `to` to the writer.
+  pub fn feed(&mut self, input: &mut Input, from: Time, to: Time, writer: &mut AudioWriter, output: &mut Output)
+              -> Result<()> {
+    input.seek(from.micros(), ..from.micros())?;
+    self.decoder.flush();
+    for (stream, packet) in input.packets() {
+      if stream.index() == self.index && self.decode(&packet, to, |frame| writer.add(frame, output))? {
+        break;
+      }
+    }
+    Ok(())
+  }
+
+  /// Seeks back to `from` and decodes the audio until `to` as stereo samples at `rate`.
+  fn read(&mut self, input: &mut Input, from: Time, to: Time, rate: i32) -> Result<Vec<(f32, f32)>> {
+    input.seek(from.micros(), ..from.micros())?;
+    self.decoder.flush();
+    let format = format!("sample_fmts=flt:sample_rates={}:channel_layouts=stereo", rate);
+    let mut graph = audio_filter(&self.buffer(), from, to, "", &format, 0)?;
+    let mut samples = vec![];
+    let mut drain = |graph: &mut filter::Graph| {
+      let mut filtered = Audio::empty();
+      while graph.get("out").unwrap().sink().frame(&mut filtered).is_ok() {
+        samples.extend_from_slice(filtered.plane::<(f32, f32)>(0));
+      }
+    };
+    for (stream, packet) in input.packets() {
+      if stream.index() != self.index { continue; }
+      let done = self.decode(&packet, to, |frame| Ok(graph.get("in").unwrap().source().add(frame)?))?;
+      drain(&mut graph);
+      if done { break; }
+    }
+    graph.get("in").unwrap().source().flush()?;
+    drain(&mut graph);
+    Ok(samples)
+  }
+
+  /// Decodes the clip from `from` to `to` and isolates its dialogue, with the noise taken from the
+  /// gaps around it.
+  fn isolate(&mut self, input: &mut Input, subtitles: &[Dialogue], index: usize, from: Time, to: Time,
+             isolation: &VocalIsolation) -> Result<Vec<(f32, f32)>> {
+    let samples = self.read(input, from, to, AUDIO_RATE)?;
+    let mut profile = None;
+    if isolation.denoise > 0.0 {
+      let mut noise = vec![];
+      for (start, end) in noise_gaps(subtitles, index) {
+        noise.extend(self.read(input, start, end, AUDIO_RATE)?);
+      }
+      profile = noise_profile(&noise, isolation.center);
+      if profile.is_none() {
+        debug!("No gap around dialogue {} to take noise from", index + 1);
+      }
+    }
+    Ok(isolate(&samples, isolation, profile.as_deref()))
+  }
+
+  /// Passes the frames decoded from `packet` on, returning true once past `to`.
+  pub fn decode(&mut self, packet: &Packet, to: Time, mut add: impl FnMut(&Audio) -> Result<()>) -> Result<bool> {
+    let mut frame = Audio::empty();
+    self.decoder.send_packet(packet)?;
+    while self.decoder.receive_frame(&mut frame).is_ok() {
+      let Some(timestamp) = frame.timestamp() else { continue; };
+      if Time::from_timestamp(timestamp, self.time_base) > to {
+        return Ok(true);
+      }
+      add(&frame)?;
+    }
+    Ok(false)
+  }
+}
+
+/// Encodes a clip as the only stream of `filename`, with the writer from `create` and the audio
+/// that `feed` adds to it.
+fn write_clip(filename: &str, tags: &ClipTags, format: AudioFormat,
+              create: impl FnOnce(&mut Output) -> Result<AudioWriter>,
+              feed: impl FnOnce(&mut AudioWriter, &mut Output) -> Result<()>) -> Result<()> {
+  let mut output = format::output(&filename)?;
+  output.set_metadata(metadata(tags, format));
+  let mut writer = create(&mut output)?;
+  if let AudioFormat::Opus | AudioFormat::Vorbis = format {
+    // Ogg keeps the comments in the stream headers
+    output.stream_mut(writer.index).ok_or(Error::StreamNotFound)?.set_metadata(metadata(tags, format));
+  }
+  if format == AudioFormat::Mp3 {
+    // The muxer only writes a cover from a picture stream, so the tag is added after the trailer
+    let mut settings = Dictionary::new();
+    settings.set("id3v2_version", "0");
+    output.write_header_with(settings)?;
+  } else {
+    output.write_header()?;
+  }
+  feed(&mut writer, &mut output)?;
+  writer.finish(&mut output)?;
+  output.write_trailer()?;
+  drop(output);
+  if format == AudioFormat::Mp3 {
+    let mut contents = tags.id3();
+    contents.extend(fs::read(filename)?);
+    fs::write(filename, contents)?;
+  }
+  Ok(())
+}
+
+pub(crate) struct AudioWriter {
+  encoder: encoder::Audio,
+  /// Trims to the clip and converts to the encoder format
+  filter: filter::Graph,
+  /// Joins the clips into frames of the encoder size
+  framer: filter::Graph,
+  format: String,
+  index: usize,
+  /// Samples passed on to the framer and to the encoder
+  joined: i64,
+  samples: i64,
+}
+
+impl AudioWriter {
+  /// Adds an audio stream to the output, with `effects` filtering the clip from `buffer`.
+  pub fn create(output: &mut Output, buffer: &str, from: Time, to: Time, effects: &str,
+                audio: &AudioEncoder) -> Result<AudioWriter> {
+    let global_header = output.format().flags().contains(format::Flags::GLOBAL_HEADER);
+    let codec = encoder::find_by_name(audio.format.codec()).ok_or(Error::EncoderNotFound)?;
+    let sample_format = codec.audio()?.formats()
+      .and_then(|mut formats| formats.next())
+      .ok_or(Error::EncoderNotFound)?;
+    let mut context = context::Context::new_with_codec(codec).encoder().audio()?;
+    context.set_rate(AUDIO_RATE);
+    let (layout, layout_name) = audio.layout();
+    context.set_channel_layout(layout);
+    context.set_format(sample_format);
+    context.set_bit_rate(audio.bitrate * 1000);
+    context.set_time_base(Rational(1, AUDIO_RATE));
+    if global_header {
+      context.set_flags(codec::Flags::GLOBAL_HEADER);
+    }
+    let encoder = context.open_as(codec)?;
+    let index = {
+      let mut stream = output.add_stream(codec)?;
+      stream.set_parameters(&encoder);
+      stream.index()
+    };
+    let format = format!("sample_fmts={}:sample_rates={}:channel_layouts={}", encoder.format().name(), AUDIO_RATE,
+                         layout_name);
+    let filter = audio_filter(buffer, from, to, effects, &format, 0)?;
+    let framer = frame_filter(&encoder)?;
+    Ok(AudioWriter { encoder, filter, framer, format, index, joined: 0, samples: 0 })
+  }
+
+  pub fn add(&mut self, frame: &Audio, output: &mut Output) -> Result<()> {
+    self.filter.get("in").unwrap().source().add(frame)?;
+    self.join(output)
+  }
+
+  /// Adds stereo samples at the encoder rate, to a writer created with `samples_buffer`.
+  pub fn add_samples(&mut self, samples: &[(f32, f32)], output: &mut Output) -> Result<()> {
+    for (index, chunk) in samples.chunks(SAMPLES_FRAME).enumerate() {
+      let mut frame = Audio::new(Sample::F32(sample::Type::Packed), chunk.len(), ChannelLayout::STEREO);
+      frame.set_rate(AUDIO_RATE as u32);
+      frame.set_pts(Some((index * SAMPLES_FRAME) as i64));
+      frame.plane_mut::<(f32, f32)>(0).copy_from_slice(chunk);
+      self.add(&frame, output)?;
+    }
+    Ok(())
+  }
+
+  /// Ends the clip so far and continues the stream with the audio from `from` to `to`.
+  pub fn append(&mut self, buffer: &str, from: Time, to: Time, effects: &str, output: &mut Output)
+                -> Result<()> {
+    self.filter.get("in").unwrap().source().flush()?;
+    self.join(output)?;
+    self.filter = audio_filter(buffer, from, to, effects, &self.format, 0)?;
+    Ok(())
+  }
+
+  pub fn finish(&mut self, output: &mut Output) -> Result<()> {
+    self.filter.get("in").unwrap().source().flush()?;
+    self.join(output)?;
+    self.framer.get("in").unwrap().source().flush()?;
+    self.encode_framed(output)?;
+    self.encoder.send_eof()?;
+    write_packets(&mut self.encoder, self.index, Rational(1, AUDIO_RATE), output)
+  }
+
+  /// Passes the filtered clip on to the framer, right after the clips before it.
+  fn join(&mut self, output: &mut Output) -> Result<()> {
+    let mut filtered = Audio::empty();
+    while self.filter.get("out").unwrap().sink().frame(&mut filtered).is_ok() {
+      filtered.set_pts(Some(self.joined));
+      self.joined += filtered.samples() as i64;
+      self.framer.get("in").unwrap().source().add(&filtered)?;
+      self.encode_framed(output)?;
+    }
+    Ok(())
+  }
+
+  fn encode_framed(&mut self, output: &mut Output) -> Result<()> {
+    let mut framed = Audio::empty();
+    while self.framer.get("out").unwrap().sink().frame(&mut framed).is_ok() {
+      framed.set_pts(Some(self.samples));
+      self.samples += framed.samples() as i64;
+      self.encoder.send_frame(&framed)?;
+      write_packets(&mut self.encoder, self.index, Rational(1, AUDIO_RATE), output)?;
+    }
+    Ok(())
+  }
+}
+
+/// Cuts the decoded audio to the clip, which is more precise than the packets it is decoded from,
+/// and converts it to the `aformat` options, in frames of `frame_size` unless 0.
+fn audio_filter(buffer: &str, from: Time, to: Time, effects: &str, format: &str, frame_size: u32)
+                -> Result<filter::Graph> {
+  let spec = format!(
+    "atrim=start={}:end={},asetpts=PTS-STARTPTS{},aformat={}",
+    seconds(from),
+    seconds(to),
+    effects,
+    format,
+  );
+  let mut graph = filter::Graph::new();
+  graph.add(&filter::find("abuffer").ok_or(Error::FilterNotFound)?, "in", buffer)?;
+  graph.add(&filter::find("abuffersink").ok_or(Error::FilterNotFound)?, "out", "")?;
+  graph.output("in", 0)?.input("out", 0)?.parse(&spec)?;
+  graph.validate()?;
+  if frame_size > 0 {
+    graph.get("out").unwrap().sink().set_frame_size(frame_size);
+  }
+  Ok(graph)
+}
+
+/// Arguments of the `abuffer` filter for samples passed to `AudioWriter::add_samples`.
+fn samples_buffer() -> String {
+  format!("time_base=1/{}:sample_rate={}:sample_fmt=flt:channel_layout=0x{:x}", AUDIO_RATE, AUDIO_RATE,
+          ChannelLayout::STEREO.bits())
+}
+
+/// Regroups audio in the encoder format into frames of its frame size, as only the last frame
+/// sent to most encoders may be shorter.
+fn frame_filter(encoder: &encoder::Audio) -> Result<filter::Graph> {
+  let args = format!(
+    "time_base=1/{}:sample_rate={}:sample_fmt={}:channel_layout=0x{:x}",
+    AUDIO_RATE,
+    AUDIO_RATE,
+    encoder.format().name(),
+    encoder.channel_layout().bits(),
+  );
+  let mut graph = filter::Graph::new();
+  graph.add(&filter::find("abuffer").ok_or(Error::FilterNotFound)?, "in", &args)?;
+  graph.add(&filter::find("abuffersink").ok_or(Error::FilterNotFound)?, "out", "")?;
+  graph.output("in", 0)?.input("out", 0)?.parse("anull")?;
+  graph.validate()?;
+  if encoder.frame_size() > 0 {
+    graph.get("out").unwrap().sink().set_frame_size(encoder.frame_size());
+  }
+  Ok(graph)
+}
+
+/// Tags with the names that the muxers write as Vorbis comments, MP4 atoms or RIFF INFO, and
+/// the cover for Ogg.
+fn metadata(tags: &ClipTags, format: AudioFormat) -> Dictionary {
+  let mut metadata = Dictionary::new();
+  metadata.set("title", &tags.title);
+  metadata.set("album", &tags.album);
+  metadata.set("track", &tags.track_number());
+  if !tags.comment.is_empty() {
+    metadata.set("comment", &tags.comment);
+  }
+  if let (AudioFormat::Opus | AudioFormat::Vorbis, Some(picture)) = (format, tags.picture_block()) {
+    metadata.set("METADATA_BLOCK_PICTURE", &picture);
+  }
+  metadata
+}
+
+/// Gain to the target loudness, limiting the true peaks that it raises on oversampled audio. The
+/// limiter is compensated for its lookahead, which would otherwise delay the clip.
+fn normalize(loudness: Option<f64>, normalization: &Normalization) -> String {
+  let gain = loudness.map(|loudness| normalization.target - loudness).unwrap_or_default();
+  let limit = 10f64.powf(normalization.peak / 20.0).clamp(0.0625, 1.0);
+  format!(",volume={:.2}dB,aresample={},alimiter=limit={:.4}:level=false:latency=1,aresample={}", gain,
+          OVERSAMPLING * AUDIO_RATE, limit, AUDIO_RATE)
+}
+
+fn lufs(loudness: Option<f64>) -> String {
+  loudness.map(|loudness| format!("{:.1} LUFS", loudness)).unwrap_or_else(|| "silence".to_string())
+}
+
+/// Fades in and out of a clip of `length`, each at most half of it.
+pub(crate) fn fades(length: Time, fade: Time) -> String {
+  let fade = fade.min(Time::from_micros(length.micros() / 2));
+  if fade <= Time::default() {
+    return String::new();
+  }
+  format!(",afade=t=in:d={},afade=t=out:st={}:d={}", seconds(fade), seconds(length - fade), seconds(fade))
+}
+
+/// Changes the tempo without changing the pitch, by overlapping and adding similar parts of the
+/// waveform. Speeds below 0.5 take several passes.
+fn stretch(speed: f64) -> String {
+  let mut speed = speed.max(0.01);
+  let mut filters = String::new();
+  while speed < 0.5 {
+    filters += ",atempo=0.5";
+    speed /= 0.5;
+  }
+  filters + &format!(",atempo={:.4}", speed)
+}
+
+fn seconds(time: Time) -> String {
+  format!("{}.{:06}", time.micros().div_euclid(1_000_000), time.micros().rem_euclid(1_000_000))
+}
+
+pub(crate) fn write_packets(encoder: &mut encoder::Encoder, index: usize, time_base: Rational, output: &mut Output)
+                            -> Result<()> {
+  let mut packet = Packet::empty();
+  while encoder.receive_packet(&mut packet).is_ok() {
+    packet.set_stream(index);
+    packet.rescale_ts(time_base, output.stream(index).ok_or(Error::StreamNotFound)?.time_base());
+    packet.write_interleaved(output)?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::image::ImageFormat;
+  use crate::offset_subtitle_file;
+  use crate::plot::PlotKind;
+
+  use super::*;
+
+  #[test]
+  fn it_formats_filter_seconds() {
+    assert_eq!("83.250000", seconds(Time::new(0, 1, 23, 250)));
+    assert_eq!("0.000000", seconds(Time::default()));
+  }
+
+  #[test]
+  fn it_fades_clip_edges() {
+    assert_eq!(",afade=t=in:d=0.010000,afade=t=out:st=1.990000:d=0.010000",
+               fades(Time::new(0, 0, 2, 0), Time::from_millis(10)));
+    assert_eq!(",afade=t=in:d=0.005000,afade=t=out:st=0.005000:d=0.005000",
+               fades(Time::from_millis(10), Time::from_millis(10)));
+    assert_eq!("", fades(Time::new(0, 0, 2, 0), Time::default()));
+  }
+
+  #[test]
+  fn it_stretches_in_passes() {
+    assert_eq!(",atempo=0.7500", stretch(0.75));
+    assert_eq!(",atempo=0.5,atempo=0.5000", stretch(0.25));
+    assert_eq!(",atempo=0.5,atempo=0.8000", stretch(0.4));
+  }
+
+  #[test]
+  fn it_normalizes_to_target() {
+    let normalization = Normalization::default();
+    assert_eq!(",volume=4.50dB,aresample=192000,alimiter=limit=0.8414:level=false:latency=1,aresample=48000",
+               normalize(Some(-20.5), &normalization));
+    let loud = Normalization { target: -23.0, peak: 0.0, ..normalization };
+    assert_eq!(",volume=-7.00dB,aresample=192000,alimiter=limit=1.0000:level=false:latency=1,aresample=48000",
+               normalize(Some(-16.0), &loud));
+    assert_eq!(",volume=0.00dB,aresample=192000,alimiter=limit=0.8414:level=false:latency=1,aresample=48000",
+               normalize(None, &normalization));
+  }
+
+  #[test]
+  fn it_extracts_audio_from_video() {
+    let dialogue = offset_subtitle_file("tests/totoro.ja.srt", &None).unwrap();
+    extract_audio_clips("totoro.mkv", "target", "totoro-mkv", &dialogue, AudioSuffix::EndTime, &Some(20),
+                        &AudioOptions::default()).unwrap();
+    let padding = AudioPadding { start: Time::from_millis(200), end: Time::from_millis(300), clamp: true };
+    let encoder = AudioEncoder { bitrate: 64, ..AudioEncoder::default() };
+    let options = AudioOptions {
+      stream: Some("jpn".into()),
+      encoder,
+      padding,
+      slow: Some(0.75),
+      plot: Some(PlotOptions { kind: PlotKind::Spectrogram, pitch: true, ..PlotOptions::default() }),
+      ..AudioOptions::default()
+    };
+    extract_audio_clips("totoro.mkv", "target", "totoro-jpn", &dialogue, AudioSuffix::None, &Some(20), &options)
+      .unwrap();
+  }
+
+  #[test]
+  fn it_encodes_each_format() {
+    let dialogue = offset_subtitle_file("tests/totoro.ja.srt", &None).unwrap();
+    let translation = offset_subtitle_file("tests/totoro.en.ass", &None).unwrap();
+    let tags = TagSource { translation: &translation, cover: Some(ImageFormat::Jpeg) };
+    for format in [AudioFormat::Mp3, AudioFormat::Opus, AudioFormat::Vorbis, AudioFormat::Aac, AudioFormat::Wav] {
+      let encoder = AudioEncoder { format, bitrate: 48, mono: true };
+      let options = AudioOptions { encoder, tags, ..AudioOptions::default() };
+      extract_audio_clips("totoro.mkv", "target", "totoro", &dialogue, AudioSuffix::None, &Some(20), &options)
+        .unwrap();
+    }
+  }
+
+  #[test]
+  fn it_normalizes_loudness() {
+    let dialogue = offset_subtitle_file("tests/totoro.ja.srt", &None).unwrap();
+    for scope in [LoudnessScope::Clip, LoudnessScope::Episode] {
+      let normalization = Normalization { scope, ..Normalization::default() };
+      let options = AudioOptions { normalization: Some(normalization), ..AudioOptions::default() };
+      extract_audio_clips("totoro.mkv", "target", "totoro-loud", &dialogue, AudioSuffix::None, &Some(20), &options)
+        .unwrap();
+    }
+  }
+
+  #[test]
+  fn it_isolates_vocals() {
+    let dialogue = offset_subtitle_file("tests/totoro.ja.srt", &None).unwrap();
+    let options = AudioOptions {
+      normalization: Some(Normalization::default()),
+      slow: Some(0.75),
+      isolation: Some(VocalIsolation { center: true, denoise: 1.0 }),
+      ..AudioOptions::default()
+    };
+    extract_audio_clips("totoro.mkv", "target", "totoro-vocals", &dialogue, AudioSuffix::None, &Some(20), &options)
+      .unwrap();
+  }
+}
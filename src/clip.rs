@@ -0,0 +1,247 @@
+use clap::ValueEnum;
+use ffmpeg_next::{Dictionary, Error, format, Rational};
+use ffmpeg_next::codec::{self, context, decoder, encoder};
+use ffmpeg_next::format::context::{Input, Output};
+use ffmpeg_next::format::{input, Pixel};
+use ffmpeg_next::media::Type;
+use ffmpeg_next::software::scaling::{context::Context, flag::Flags};
+use ffmpeg_next::util::frame::video::Video;
+use log::info;
+
+use crate::{Dialogue, Result, sample_range, Time};
+use crate::audio::{AudioEncoder, AudioFormat, AudioSource, AudioWriter, write_packets};
+use crate::ffmpeg::{display_size, output_size, set_color_details};
+
+/// Video packets are timed in milliseconds.
+const VIDEO_TIME_BASE: Rational = Rational(1, 1000);
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum ClipFormat {
+  /// H.264 with AAC
+  Mp4,
+  /// VP9 with Opus
+  Webm,
+}
+
+impl ClipFormat {
+  pub fn extension(&self) -> &'static str {
+    match self {
+      ClipFormat::Mp4 => "mp4",
+      ClipFormat::Webm => "webm",
+    }
+  }
+
+  fn video_codec(&self) -> &'static str {
+    match self {
+      ClipFormat::Mp4 => "libx264",
+      ClipFormat::Webm => "libvpx-vp9",
+    }
+  }
+
+  fn audio_format(&self) -> AudioFormat {
+    match self {
+      ClipFormat::Mp4 => AudioFormat::Aac,
+      ClipFormat::Webm => AudioFormat::Opus,
+    }
+  }
+
+  /// Fast settings, as a clip is short and small.
+  fn video_settings(&self) -> Dictionary {
+    let mut settings = Dictionary::new();
+    match self {
+      ClipFormat::Mp4 => settings.set("preset", "veryfast"),
+      ClipFormat::Webm => {
+        settings.set("deadline", "realtime");
+        settings.set("cpu-used", "8");
+      }
+    }
+    settings
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ClipOptions {
+  pub format: ClipFormat,
+  /// Height of the clip, keeping the aspect ratio
+  pub height: u32,
+  /// Video bitrate in kbit/s
+  pub video_bitrate: usize,
+  /// Audio bitrate in kbit/s
+  pub audio_bitrate: usize,
+  /// Included before the dialogue starts
+  pub pad_start: Time,
+  /// Included after the dialogue ends
+  pub pad_end: Time,
+}
+
+impl Default for ClipOptions {
+  fn default() -> Self {
+    ClipOptions {
+      format: ClipFormat::Mp4,
+      height: 360,
+      video_bitrate: 600,
+      audio_bitrate: 96,
+      pad_start: Time::from_millis(250),
+      pad_end: Time::from_millis(250),
+    }
+  }
+}
+
+/// Encodes a short video clip with audio of each dialogue, named like the screenshots.
+pub fn extract_video_clips(video_file: &str, folder: &str, prefix: &str, subtitles: &[Dialogue], sample: &Option<u32>,
+                           options: &ClipOptions) -> Result<()> {
+  ffmpeg_next::init()?;
+  info!("Extracting clips from {}", video_file);
+
+  let (start, end) = sample_range(sample);
+  let mut source = ClipSource::open(video_file)?;
+  for dialogue in subtitles.iter().filter(|d| d.start >= start && d.start <= end) {
+    let from = (dialogue.start - options.pad_start).max(Time::default());
+    let to = dialogue.end + options.pad_end;
+    let filename = format!("{}/{}_{}.{}", folder, prefix, dialogue.start.hms(), options.format.extension());
+    info!("Saving {}", filename);
+    source.clip(&filename, from, to, options)?;
+  }
+  info!("Done!");
+  Ok(())
+}
+
+struct ClipSource {
+  input: Input,
+  video: decoder::Video,
+  video_index: usize,
+  video_time_base: Rational,
+  frame_rate: Rational,
+  audio: Option<AudioSource>,
+}
+
+impl ClipSource {
+  fn open(video_file: &str) -> Result<ClipSource> {
+    let input = input(&video_file)?;
+    let stream = input.streams().best(Type::Video).ok_or(Error::StreamNotFound)?;
+    let video_index = stream.index();
+    let video_time_base = stream.time_base();
+    let frame_rate = stream.avg_frame_rate();
+    let video = context::Context::from_parameters(stream.parameters())?.decoder().video()?;
+    let audio = input.streams().best(Type::Audio).map(|stream| AudioSource::open(&stream)).transpose()?;
+    Ok(ClipSource { input, video, video_index, video_time_base, frame_rate, audio })
+  }
+
+  /// Seeks back to `from` and encodes until both streams pass `to`.
+  fn clip(&mut self, filename: &str, from: Time, to: Time, options: &ClipOptions) -> Result<()> {
+    self.input.seek(from.micros(), ..from.micros())?;
+    self.video.flush();
+    if let Some(audio) = self.audio.as_mut() {
+      audio.decoder.flush();
+    }
+    let mut writer = ClipWriter::create(filename, &self.video, self.frame_rate, self.audio.as_ref(), from, to,
+                                        options)?;
+
+    let mut video_frame = Video::empty();
+    let mut video_done = false;
+    let mut audio_done = self.audio.is_none();
+    for (stream, packet) in self.input.packets() {
+      if stream.index() == self.video_index && !video_done {
+        self.video.send_packet(&packet)?;
+        while self.video.receive_frame(&mut video_frame).is_ok() {
+          let Some(timestamp) = video_frame.timestamp() else { continue; };
+          let time = Time::from_timestamp(timestamp, self.video_time_base);
+          if time > to {
+            video_done = true;
+            break;
+          }
+          if time >= from {
+            writer.add_video(&video_frame, time - from)?;
+          }
+        }
+      }
+      if let Some(audio) = self.audio.as_mut().filter(|audio| audio.index == stream.index() && !audio_done) {
+        if let Some(audio_writer) = writer.audio.as_mut() {
+          audio_done = audio.decode(&packet, to, |frame| audio_writer.add(frame, &mut writer.output))?;
+        }
+      }
+      if video_done && audio_done {
+        break;
+      }
+    }
+    writer.finish()
+  }
+}
+
+struct ClipWriter {
+  output: Output,
+  scaler: Context,
+  video: encoder::Video,
+  audio: Option<AudioWriter>,
+}
+
+impl ClipWriter {
+  fn create(filename: &str, video: &decoder::Video, frame_rate: Rational, audio: Option<&AudioSource>, from: Time,
+            to: Time, options: &ClipOptions) -> Result<ClipWriter> {
+    let mut output = format::output(&filename)?;
+    let global_header = output.format().flags().contains(format::Flags::GLOBAL_HEADER);
+
+    let source = display_size((video.width(), video.height()), video.aspect_ratio());
+    let (width, height) = output_size(source, None, Some(options.height));
+    let codec = encoder::find_by_name(options.format.video_codec()).ok_or(Error::EncoderNotFound)?;
+    let mut context = context::Context::new_with_codec(codec).encoder().video()?;
+    context.set_width(width);
+    context.set_height(height);
+    context.set_format(Pixel::YUV420P);
+    context.set_time_base(VIDEO_TIME_BASE);
+    if frame_rate.numerator() > 0 {
+      context.set_frame_rate(Some(frame_rate));
+    }
+    context.set_bit_rate(options.video_bitrate * 1000);
+    if global_header {
+      context.set_flags(codec::Flags::GLOBAL_HEADER);
+    }
+    let video_encoder = context.open_with(options.format.video_settings())?;
+    output.add_stream(codec)?.set_parameters(&video_encoder);
+    let mut scaler = Context::get(video.format(), video.width(), video.height(), Pixel::YUV420P, width, height,
+                                  Flags::BILINEAR)?;
+    set_color_details(&mut scaler, video, Some(height))?;
+
+    let encoder = AudioEncoder { format: options.format.audio_format(), bitrate: options.audio_bitrate, mono: false };
+    let audio = match audio {
+      None => None,
+      Some(audio) => Some(AudioWriter::create(&mut output, &audio.buffer(), from, to, "", &encoder)?),
+    };
+    output.write_header()?;
+    Ok(ClipWriter { output, scaler, video: video_encoder, audio })
+  }
+
+  /// Adds a frame shown at `time` into the clip.
+  fn add_video(&mut self, frame: &Video, time: Time) -> Result<()> {
+    let mut scaled = Video::empty();
+    self.scaler.run(frame, &mut scaled)?;
+    scaled.set_pts(Some(time.timestamp(VIDEO_TIME_BASE)));
+    self.video.send_frame(&scaled)?;
+    write_packets(&mut self.video, 0, VIDEO_TIME_BASE, &mut self.output)
+  }
+
+  fn finish(mut self) -> Result<()> {
+    self.video.send_eof()?;
+    write_packets(&mut self.video, 0, VIDEO_TIME_BASE, &mut self.output)?;
+    if let Some(audio) = self.audio.as_mut() {
+      audio.finish(&mut self.output)?;
+    }
+    self.output.write_trailer()?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::offset_subtitle_file;
+
+  use super::*;
+
+  #[test]
+  fn it_extracts_video_clips() {
+    let dialogue = offset_subtitle_file("tests/totoro.ja.srt", &None).unwrap();
+    extract_video_clips("totoro.mkv", "target", "totoro", &dialogue, &Some(20), &ClipOptions::default()).unwrap();
+    let options = ClipOptions { format: ClipFormat::Webm, height: 240, ..ClipOptions::default() };
+    extract_video_clips("totoro.mkv", "target", "totoro", &dialogue, &Some(20), &options).unwrap();
+  }
+}
@@ -0,0 +1,164 @@
+use ffmpeg_next::{Error, format, Rational};
+use ffmpeg_next::format::input;
+use log::{debug, info};
+
+use crate::{Dialogue, Result, sample_range, Time};
+use crate::audio::{AudioEncoder, AudioSource, AudioWriter, fades, select_stream};
+use crate::mp3::AudioPadding;
+
+/// Chapters are timed in microseconds, like `Time`.
+const CHAPTER_TIME_BASE: Rational = Rational(1, 1_000_000);
+
+#[derive(Clone, Debug)]
+pub struct CondenseOptions {
+  /// Audio stream index or language code, instead of the default stream
+  pub stream: Option<String>,
+  pub encoder: AudioEncoder,
+  pub padding: AudioPadding,
+  /// Dialogue closer together than this is kept with the silence between it
+  pub gap: Time,
+  /// Fade in and out where the dialogue is joined
+  pub fade: Time,
+  /// Adds a chapter for each part of the episode this many minutes long
+  pub chapter_minutes: Option<u32>,
+}
+
+impl Default for CondenseOptions {
+  fn default() -> Self {
+    CondenseOptions {
+      stream: None,
+      encoder: AudioEncoder::default(),
+      padding: AudioPadding { start: Time::from_millis(250), end: Time::from_millis(250), clamp: false },
+      gap: Time::from_millis(1000),
+      fade: Time::from_millis(10),
+      chapter_minutes: None,
+    }
+  }
+}
+
+/// Part of the condensed audio, from the dialogue of a part of the episode.
+#[derive(Debug, PartialEq)]
+struct Chapter {
+  start: Time,
+  end: Time,
+  title: String,
+}
+
+/// Decodes the audio of a video or audio file and encodes only the dialogue, one after the other,
+/// into a single file for passive listening.
+pub fn condense_audio(media_file: &str, folder: &str, prefix: &str, subtitles: &[Dialogue], sample: &Option<u32>,
+                      options: &CondenseOptions) -> Result<()> {
+  ffmpeg_next::init()?;
+  let filename = format!("{}/{}-condensed.{}", folder, prefix, options.encoder.format.extension());
+  info!("Condensing the dialogue of {} into {}", media_file, filename);
+
+  let (start, end) = sample_range(sample);
+  let ranges = subtitles.iter().enumerate()
+    .filter(|(_, d)| d.start >= start && d.start <= end)
+    .map(|(index, _)| options.padding.range(subtitles, index))
+    .collect();
+  let intervals = join_intervals(ranges, options.gap);
+  let Some(&(from, to)) = intervals.first() else {
+    info!("No dialogue to condense");
+    return Ok(());
+  };
+
+  let mut input = input(&media_file)?;
+  let stream = select_stream(&input, options.stream.as_deref()).ok_or(Error::StreamNotFound)?;
+  let mut source = AudioSource::open(&stream)?;
+  let mut output = format::output(&filename)?;
+  if let Some(minutes) = options.chapter_minutes {
+    for (id, chapter) in chapters(&intervals, minutes).iter().enumerate() {
+      debug!("Chapter {} at {}", chapter.title, chapter.start);
+      output.add_chapter(id as i64, CHAPTER_TIME_BASE, chapter.start.micros(), chapter.end.micros(), &chapter.title)?;
+    }
+  }
+  let effects = fades(to - from, options.fade);
+  let buffer = source.buffer();
+  let mut writer = AudioWriter::create(&mut output, &buffer, from, to, &effects, &options.encoder)?;
+  output.write_header()?;
+  for (index, &(from, to)) in intervals.iter().enumerate() {
+    if index > 0 {
+      writer.append(&buffer, from, to, &fades(to - from, options.fade), &mut output)?;
+    }
+    source.feed(&mut input, from, to, &mut writer, &mut output)?;
+  }
+  writer.finish(&mut output)?;
+  output.write_trailer()?;
+  let length = intervals.iter().fold(Time::default(), |length, &(from, to)| length + (to - from));
+  info!("Done! {} seconds of dialogue in {} parts", length.milliseconds() / 1000, intervals.len());
+  Ok(())
+}
+
+/// Sorts the clips and joins those that overlap or are less than `gap` apart.
+fn join_intervals(mut ranges: Vec<(Time, Time)>, gap: Time) -> Vec<(Time, Time)> {
+  ranges.sort();
+  let mut intervals: Vec<(Time, Time)> = vec![];
+  for (from, to) in ranges {
+    match intervals.last_mut() {
+      Some((_, end)) if from - *end < gap => *end = (*end).max(to),
+      _ => intervals.push((from, to)),
+    }
+  }
+  intervals
+}
+
+/// Chapters of the condensed audio for each part of the episode with dialogue, titled by the
+/// minutes of the episode that they are from.
+fn chapters(intervals: &[(Time, Time)], minutes: u32) -> Vec<Chapter> {
+  let minutes = minutes.max(1) as i64;
+  let length = Time::new(0, minutes as u32, 0, 0).micros();
+  let mut chapters: Vec<Chapter> = vec![];
+  let mut part = None;
+  let mut position = Time::default();
+  for &(from, to) in intervals {
+    let current = from.micros() / length;
+    if part != Some(current) {
+      part = Some(current);
+      let title = format!("{}-{} min", current * minutes, (current + 1) * minutes);
+      chapters.push(Chapter { start: position, end: position, title });
+    }
+    position = position + (to - from);
+    if let Some(chapter) = chapters.last_mut() {
+      chapter.end = position;
+    }
+  }
+  chapters
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::offset_subtitle_file;
+
+  use super::*;
+
+  fn seconds(from: u32, to: u32) -> (Time, Time) {
+    (Time::new(0, 0, from, 0), Time::new(0, 0, to, 0))
+  }
+
+  #[test]
+  fn it_joins_close_dialogue() {
+    let ranges = vec![seconds(10, 12), seconds(1, 3), seconds(2, 5), seconds(5, 6), seconds(7, 9)];
+    assert_eq!(vec![seconds(1, 6), seconds(7, 9), seconds(10, 12)],
+               join_intervals(ranges.clone(), Time::from_millis(1000)));
+    assert_eq!(vec![seconds(1, 12)], join_intervals(ranges, Time::from_millis(1001)));
+  }
+
+  #[test]
+  fn it_splits_chapters_by_episode_minutes() {
+    let intervals = vec![seconds(10, 20), seconds(290, 300), seconds(310, 315), seconds(1000, 1002)];
+    let chapters = chapters(&intervals, 5);
+    assert_eq!(vec![
+      Chapter { start: Time::default(), end: Time::new(0, 0, 20, 0), title: "0-5 min".into() },
+      Chapter { start: Time::new(0, 0, 20, 0), end: Time::new(0, 0, 25, 0), title: "5-10 min".into() },
+      Chapter { start: Time::new(0, 0, 25, 0), end: Time::new(0, 0, 27, 0), title: "15-20 min".into() },
+    ], chapters);
+  }
+
+  #[test]
+  fn it_condenses_audio() {
+    let dialogue = offset_subtitle_file("tests/totoro.ja.srt", &None).unwrap();
+    let options = CondenseOptions { chapter_minutes: Some(1), ..CondenseOptions::default() };
+    condense_audio("totoro.mkv", "target", "totoro", &dialogue, &Some(20), &options).unwrap();
+  }
+}
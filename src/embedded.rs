@@ -0,0 +1,255 @@
+use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
+use std::slice;
+
+use ffmpeg_next::{Error, Packet, Rational, Subtitle};
+use ffmpeg_next::codec::context;
+use ffmpeg_next::format::input;
+use ffmpeg_next::media::Type;
+use ffmpeg_next::subtitle::{Bitmap, Rect};
+use log::{debug, info};
+
+use crate::{Dialogue, Result, Time};
+
+/// Bitmap subtitles without an end time are cleared by the next display set, except the last one.
+const LAST_DISPLAY_MS: i64 = 5000;
+
+#[derive(Debug)]
+pub struct SubtitleStream {
+  pub index: usize,
+  pub codec: &'static str,
+  pub language: Option<String>,
+  pub title: Option<String>,
+}
+
+impl fmt::Display for SubtitleStream {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}: {} [{}] {}", self.index, self.codec,
+           self.language.as_deref().unwrap_or("und"),
+           self.title.as_deref().unwrap_or(""))
+  }
+}
+
+impl SubtitleStream {
+  fn is_text(&self) -> bool {
+    matches!(self.codec, "ass" | "ssa" | "subrip" | "srt" | "text" | "mov_text" | "webvtt")
+  }
+
+  fn is_bitmap(&self) -> bool {
+    matches!(self.codec, "hdmv_pgs_subtitle" | "dvd_subtitle" | "dvb_subtitle" | "xsub")
+  }
+}
+
+pub fn list_subtitle_streams(video_file: &str) -> Result<Vec<SubtitleStream>> {
+  ffmpeg_next::init()?;
+  let input = input(&video_file)?;
+  let streams = input
+    .streams()
+    .filter(|stream| stream.parameters().medium() == Type::Subtitle)
+    .map(|stream| {
+      let metadata = stream.metadata();
+      SubtitleStream {
+        index: stream.index(),
+        codec: stream.parameters().id().name(),
+        language: metadata.get("language").map(String::from),
+        title: metadata.get("title").map(String::from),
+      }
+    })
+    .collect();
+  Ok(streams)
+}
+
+/// Selects a stream by its container index (as in `-map 0:2`) or by language code, preferring text
+/// over bitmap streams.
+fn select_stream<'a>(streams: &'a [SubtitleStream], selector: &str) -> Option<&'a SubtitleStream> {
+  if let Ok(index) = selector.parse::<usize>() {
+    return streams.iter().find(|stream| stream.index == index);
+  }
+  let language = |stream: &&SubtitleStream| stream.language.as_deref() == Some(selector);
+  streams.iter().filter(|stream| stream.is_text()).find(language)
+    .or_else(|| streams.iter().filter(|stream| stream.is_bitmap()).find(language))
+}
+
+pub fn offset_subtitle_stream(video_file: &str, selector: &str, folder: &str, prefix: &str, offset: &Option<f32>)
+                              -> Result<Vec<Dialogue>> {
+  let mut dialogue = extract_subtitle_stream(video_file, selector, folder, prefix)?;
+  if let Some(offset) = offset {
+    for d in dialogue.iter_mut() {
+      d.shift(*offset);
+    }
+  }
+  Ok(dialogue)
+}
+
+/// Decodes a text or bitmap subtitle stream. Bitmap subtitles are saved as PNG in `folder` and
+/// referenced from the dialogue, with the text left blank.
+pub fn extract_subtitle_stream(video_file: &str, selector: &str, folder: &str, prefix: &str)
+                               -> Result<Vec<Dialogue>> {
+  let streams = list_subtitle_streams(video_file)?;
+  let selected = select_stream(&streams, selector).ok_or(Error::StreamNotFound)?;
+  info!("Extracting subtitles from {} stream {}", video_file, selected);
+
+  let mut input = input(&video_file)?;
+  let stream = input.stream(selected.index).ok_or(Error::StreamNotFound)?;
+  let time_base = stream.time_base();
+  let mut decoder = context::Context::from_parameters(stream.parameters())?.decoder();
+  decoder.set_packet_time_base(time_base);
+  let mut decoder = decoder.subtitle()?;
+
+  let mut list: Vec<Dialogue> = vec![];
+  let mut open = false;
+  for (stream, packet) in input.packets() {
+    if stream.index() != selected.index { continue; }
+    let mut subtitle = Subtitle::new();
+    if !decoder.decode(&packet, &mut subtitle)? { continue; }
+    let Some((start, end)) = display_time(&subtitle, &packet, time_base) else { continue; };
+
+    if open {
+      if let Some(last) = list.last_mut() {
+        last.end = start;
+      }
+      open = false;
+    }
+
+    let display_end = end.unwrap_or(start + Time::from_millis(LAST_DISPLAY_MS));
+    let dialogue = if selected.is_bitmap() {
+      let image = format!("{}_{}_subtitle.png", prefix, start.milliseconds());
+      if !save_bitmaps(&subtitle, format!("{}/{}", folder, image))? { continue; }
+      Dialogue { start, end: display_end, text: String::new(), image: Some(image) }
+    } else {
+      let text = subtitle.rects()
+        .filter_map(|rect| rect_text(&rect))
+        .collect::<Vec<_>>()
+        .join("\\N");
+      if text.is_empty() { continue; }
+      Dialogue { start, end: display_end, text, image: None }
+    };
+
+    open = end.is_none();
+    debug!("{}", dialogue);
+    list.push(dialogue);
+  }
+  Ok(list)
+}
+
+/// Display start and, when known, end. Display times are in milliseconds relative to the packet.
+fn display_time(subtitle: &Subtitle, packet: &Packet, time_base: Rational) -> Option<(Time, Option<Time>)> {
+  let pts = Time::from_timestamp(packet.pts()?, time_base);
+  let end = match subtitle.end() {
+    0 | u32::MAX => match packet.duration() {
+      0 => None,
+      duration => Some(pts + Time::from_timestamp(duration, time_base)),
+    },
+    end => Some(pts + Time::from_millis(end as i64)),
+  };
+  Some((pts + Time::from_millis(subtitle.start() as i64), end))
+}
+
+fn rect_text(rect: &Rect) -> Option<String> {
+  match rect {
+    Rect::Text(text) => Some(text.get().trim_end().to_string()),
+    Rect::Ass(ass) => ass_event_text(ass.get()),
+    _ => None,
+  }
+}
+
+/// Decoded ASS events are `ReadOrder,Layer,Style,Name,MarginL,MarginR,MarginV,Effect,Text`.
+fn ass_event_text(event: &str) -> Option<String> {
+  event.splitn(9, ',').nth(8)
+    .map(|text| text.trim_end().to_string())
+    .filter(|text| !text.is_empty())
+}
+
+/// Combines all bitmap rectangles of a display set on one transparent canvas and saves it as PNG.
+/// Returns false for the empty display sets that clear the screen.
+fn save_bitmaps(subtitle: &Subtitle, filename: String) -> Result<bool> {
+  let bitmaps = subtitle.rects()
+    .filter_map(|rect| match rect {
+      Rect::Bitmap(bitmap) => Some(bitmap),
+      _ => None
+    })
+    .filter(|bitmap| bitmap.width() > 0 && bitmap.height() > 0)
+    .collect::<Vec<_>>();
+  if bitmaps.is_empty() {
+    return Ok(false);
+  }
+
+  let left = bitmaps.iter().map(|b| b.x()).min().unwrap();
+  let top = bitmaps.iter().map(|b| b.y()).min().unwrap();
+  let width = bitmaps.iter().map(|b| b.x() + b.width() as usize).max().unwrap() - left;
+  let height = bitmaps.iter().map(|b| b.y() + b.height() as usize).max().unwrap() - top;
+
+  let mut canvas = vec![0u8; width * height * 4];
+  for bitmap in bitmaps.iter() {
+    let rgba = bitmap_to_rgba(bitmap);
+    let row_bytes = bitmap.width() as usize * 4;
+    for (row, line) in rgba.chunks_exact(row_bytes).enumerate() {
+      let offset = ((bitmap.y() - top + row) * width + bitmap.x() - left) * 4;
+      canvas[offset..offset + row_bytes].copy_from_slice(line);
+    }
+  }
+
+  info!("Saving {}", filename);
+  let writer = BufWriter::new(File::create(filename)?);
+  let mut encoder = png::Encoder::new(writer, width as u32, height as u32);
+  encoder.set_color(png::ColorType::Rgba);
+  encoder.set_depth(png::BitDepth::Eight);
+  encoder.write_header()?.write_image_data(&canvas)?;
+  Ok(true)
+}
+
+/// Bitmap rectangles are PAL8, with a palette of native endian ARGB values.
+fn bitmap_to_rgba(bitmap: &Bitmap) -> Vec<u8> {
+  let width = bitmap.width() as usize;
+  let height = bitmap.height() as usize;
+  let mut rgba = Vec::with_capacity(width * height * 4);
+  unsafe {
+    let rect = &*bitmap.as_ptr();
+    let palette = slice::from_raw_parts(rect.data[1] as *const u32, rect.nb_colors as usize);
+    for row in 0..height {
+      let line = slice::from_raw_parts(rect.data[0].add(row * rect.linesize[0] as usize), width);
+      for index in line {
+        let argb = palette.get(*index as usize).copied().unwrap_or(0);
+        rgba.extend_from_slice(&[(argb >> 16) as u8, (argb >> 8) as u8, argb as u8, (argb >> 24) as u8]);
+      }
+    }
+  }
+  rgba
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn it_takes_text_from_ass_event() {
+    let text = ass_event_text("3,0,Default,,0,0,0,,はやく！\\Nはやく！\r\n");
+    assert_eq!(Some("はやく！\\Nはやく！".to_string()), text);
+    assert_eq!(None, ass_event_text("3,0,Default,,0,0,0,,"));
+  }
+
+  #[test]
+  fn it_selects_by_index_or_language() {
+    let streams = vec![
+      SubtitleStream { index: 2, codec: "hdmv_pgs_subtitle", language: Some("jpn".into()), title: None },
+      SubtitleStream { index: 3, codec: "ass", language: Some("jpn".into()), title: None },
+      SubtitleStream { index: 4, codec: "subrip", language: Some("eng".into()), title: None },
+      SubtitleStream { index: 5, codec: "dvd_subtitle", language: Some("chi".into()), title: None },
+    ];
+    assert_eq!(2, select_stream(&streams, "2").unwrap().index);
+    assert_eq!(3, select_stream(&streams, "jpn").unwrap().index);
+    assert_eq!(4, select_stream(&streams, "eng").unwrap().index);
+    assert_eq!(5, select_stream(&streams, "chi").unwrap().index);
+    assert!(select_stream(&streams, "fre").is_none());
+  }
+
+  #[test]
+  fn it_extracts_embedded_subtitles() {
+    for stream in list_subtitle_streams("totoro.mkv").unwrap() {
+      println!("{}", stream);
+    }
+    let dialogue = extract_subtitle_stream("totoro.mkv", "jpn", "target", "totoro").unwrap();
+    assert!(!dialogue.is_empty());
+  }
+}
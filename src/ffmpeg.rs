@@ -1,93 +1,811 @@
 use std::path::Path;
 use std::process::{Command, ExitStatus};
+use std::str::FromStr;
+use std::thread;
 
-use ffmpeg_next::codec::context;
-use ffmpeg_next::Error;
+use clap::ValueEnum;
+use ffmpeg_next::{Error, filter, Rational};
+use ffmpeg_next::codec::{context, decoder};
+use ffmpeg_next::color::{Range, Space};
+use ffmpeg_next::ffi::{av_frame_apply_cropping, av_frame_ref, AVPixelFormat, sws_getCoefficients,
+                       sws_setColorspaceDetails, SWS_CS_BT2020, SWS_CS_DEFAULT, SWS_CS_FCC, SWS_CS_ITU601,
+                       SWS_CS_ITU709, SWS_CS_SMPTE240M};
 use ffmpeg_next::format::{input, Pixel};
+use ffmpeg_next::format::context::Input;
 use ffmpeg_next::media::Type;
-use ffmpeg_next::software::scaling::{context::Context, flag::Flags};
+use ffmpeg_next::software::scaling::{context::Context, context::Definition, flag::Flags};
 use ffmpeg_next::util::frame::video::Video;
-use jpeg_encoder::{ColorType, Encoder};
-use log::info;
+use log::{debug, info};
 
-use crate::{Dialogue, sample_range, Time};
+use crate::{Dialogue, MICROSECONDS, sample_range, Time};
+use crate::image::{AnimationEncoder, ImageEncoder};
 use crate::Result;
 
-pub fn extract_screenshots(video_file: &str, folder: &str, prefix: &str, subtitles: &[Dialogue], sample: &Option<u32>)
-                           -> Result<()> {
-  ffmpeg_next::init().unwrap();
+/// Screenshots further apart than this on average are reached faster by seeking to the nearest
+/// keyframe than by decoding every frame in between.
+const SEEK_SPACING: Time = Time::from_micros(15_000_000);
+
+/// Average luma below which a border row or column counts as black, like `cropdetect`.
+const BLACK_LIMIT: usize = 24;
+
+/// Frames compared within each dialogue when looking for the sharpest one.
+const FRAME_CANDIDATES: i64 = 5;
+
+/// Width of the grayscale copy that candidate frames are compared on.
+const ANALYSIS_WIDTH: u32 = 320;
+
+/// Mean luma difference between neighbouring candidates that counts as a scene cut.
+const SCENE_CHANGE: f64 = 30.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum FrameSelection {
+  /// First frame after the middle of the dialogue
+  Midpoint,
+  /// Sharpest frame that is not black, within the scene at the middle of the dialogue
+  Sharpest,
+}
+
+impl FrameSelection {
+  fn candidates(&self) -> i64 {
+    match self {
+      FrameSelection::Midpoint => 1,
+      FrameSelection::Sharpest => FRAME_CANDIDATES,
+    }
+  }
+}
+
+/// Frames sampled across the dialogue to detect black bars.
+const CROP_SAMPLES: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum ScalerAlgorithm {
+  FastBilinear,
+  Bilinear,
+  Bicubic,
+  Area,
+  Lanczos,
+  Spline,
+}
+
+impl ScalerAlgorithm {
+  fn flags(&self) -> Flags {
+    match self {
+      ScalerAlgorithm::FastBilinear => Flags::FAST_BILINEAR,
+      ScalerAlgorithm::Bilinear => Flags::BILINEAR,
+      ScalerAlgorithm::Bicubic => Flags::BICUBIC,
+      ScalerAlgorithm::Area => Flags::AREA,
+      ScalerAlgorithm::Lanczos => Flags::LANCZOS,
+      ScalerAlgorithm::Spline => Flags::SPLINE,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CropRect {
+  pub width: u32,
+  pub height: u32,
+  pub x: u32,
+  pub y: u32,
+}
+
+impl CropRect {
+  /// Whether the area is inside a frame of this size and not empty.
+  fn fits(&self, width: u32, height: u32) -> bool {
+    self.width > 0 && self.height > 0
+      && self.x.checked_add(self.width).is_some_and(|right| right <= width)
+      && self.y.checked_add(self.height).is_some_and(|bottom| bottom <= height)
+  }
+}
+
+/// Either `auto` to detect black bars, or `width:height:x:y` like the `crop` filter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Crop {
+  Auto,
+  Rect(CropRect),
+}
+
+impl FromStr for Crop {
+  type Err = String;
+
+  fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+    if value == "auto" {
+      return Ok(Crop::Auto);
+    }
+    let parts = value.split(':')
+      .map(|part| part.parse::<u32>())
+      .collect::<std::result::Result<Vec<_>, _>>()
+      .map_err(|e| format!("invalid crop {}: {}", value, e))?;
+    match parts[..] {
+      [width, height, x, y] => Ok(Crop::Rect(CropRect { width, height, x, y })),
+      [width, height] => Ok(Crop::Rect(CropRect { width, height, x: 0, y: 0 })),
+      _ => Err(format!("expected auto or width:height:x:y, not {}", value)),
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct ScreenshotOptions {
+  /// Number of parallel decoders
+  pub jobs: usize,
+  /// Output width, preserving the aspect ratio when the height is not set
+  pub width: Option<u32>,
+  /// Output height, preserving the aspect ratio when the width is not set
+  pub height: Option<u32>,
+  pub crop: Option<Crop>,
+  pub scaler: ScalerAlgorithm,
+  pub image: ImageEncoder,
+  pub frame: FrameSelection,
+  /// Deinterlace with yadif, for DVD and broadcast sources
+  pub deinterlace: bool,
+  /// Substation Alpha file drawn onto the screenshots with the `ass` filter
+  pub burn_in: Option<String>,
+  /// Animated clip of each dialogue, saved next to the screenshot
+  pub animation: Option<AnimationOptions>,
+  /// Screenshots taken from equal parts of each dialogue, numbered from 1 when there is more than one
+  pub screenshots: usize,
+  /// Tile the screenshots of each dialogue into one image instead of numbering them
+  pub contact_sheet: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AnimationOptions {
+  pub encoder: AnimationEncoder,
+  /// Width of the clip, keeping the aspect ratio
+  pub width: u32,
+  /// Longer dialogue is cut off after this
+  pub max_length: Time,
+}
+
+impl Default for ScreenshotOptions {
+  fn default() -> Self {
+    ScreenshotOptions {
+      jobs: 1,
+      width: None,
+      height: None,
+      crop: None,
+      scaler: ScalerAlgorithm::Bilinear,
+      image: ImageEncoder::default(),
+      frame: FrameSelection::Midpoint,
+      deinterlace: false,
+      burn_in: None,
+      animation: None,
+      screenshots: 1,
+      contact_sheet: false,
+    }
+  }
+}
+
+/// Extracts screenshots of each dialogue. With more than one job the dialogue is split into
+/// consecutive segments that are decoded in parallel, each with its own input and decoder.
+pub fn extract_screenshots(video_file: &str, folder: &str, prefix: &str, subtitles: &[Dialogue], sample: &Option<u32>,
+                           options: &ScreenshotOptions) -> Result<()> {
+  ffmpeg_next::init()?;
   info!("Extracting screenshots from {}", video_file);
 
-  if let Ok(mut input) = input(&video_file) {
+  let (start, end) = sample_range(sample);
+  let targets: Vec<(&Dialogue, Time)> = subtitles.iter()
+    .map(|dialogue| (dialogue, dialogue.start.half_way(&dialogue.end)))
+    .filter(|(_, half)| *half > start && *half <= end)
+    .collect();
+  if targets.is_empty() { return Ok(()); }
+
+  let crop = match options.crop {
+    None => None,
+    Some(Crop::Rect(rect)) => {
+      let video = VideoFrames::open(video_file, false)?.video;
+      if !rect.fits(video.width(), video.height()) {
+        return Err(format!("crop {}:{}:{}:{} is outside the {}x{} video", rect.width, rect.height, rect.x, rect.y,
+                           video.width(), video.height()).into());
+      }
+      Some(rect)
+    }
+    Some(Crop::Auto) => detect_crop(video_file, &targets)?,
+  };
+
+  let segment_size = targets.len().div_ceil(options.jobs.max(1));
+  thread::scope(|scope| {
+    let workers = targets.chunks(segment_size)
+      .map(|segment| scope.spawn(move || {
+        extract_segment(video_file, folder, prefix, segment, crop, options).map_err(|e| e.to_string())
+      }))
+      .collect::<Vec<_>>();
+    workers.into_iter()
+      .try_for_each(|worker| worker.join().expect("Screenshot worker panicked"))
+  })?;
+  info!("Done!");
+  Ok(())
+}
+
+fn extract_segment(video_file: &str, folder: &str, prefix: &str, targets: &[(&Dialogue, Time)],
+                   crop: Option<CropRect>, options: &ScreenshotOptions) -> Result<()> {
+  let mut frames = VideoFrames::open(video_file, options.deinterlace)?;
+  let mut scaler = create_scaler(&frames.video, crop, options.width, options.height, options.scaler)?;
+  let mut analyzer = match options.frame {
+    FrameSelection::Midpoint => None,
+    FrameSelection::Sharpest => Some(FrameAnalyzer::new(&frames.video, crop)?),
+  };
+  let mut burner = match &options.burn_in {
+    None => None,
+    Some(subtitles) => Some(burner(scaler.output(), subtitles)?),
+  };
+  let mut animator = match &options.animation {
+    None => None,
+    Some(animation) => Some(Animator::new(&frames.video, crop, animation, options.scaler)?),
+  };
+
+  let sparse = is_sparse(targets);
+  if sparse {
+    debug!("Seeking to each of {} screenshots", targets.len());
+  }
+  for (index, (dialogue, _)) in targets.iter().enumerate() {
+    let spans = screenshot_spans(dialogue, options.screenshots);
+    let first = candidate_times(spans[0], options.frame.candidates())[0];
+    if let Some(animator) = animator.as_mut() {
+      if sparse || index == 0 || frames.is_past(dialogue.start) {
+        frames.seek(dialogue.start)?;
+      }
+      let animation_file = format!("{}/{}_{}_animated", folder, prefix, dialogue.start.hms());
+      animator.save(&mut frames, dialogue, &animation_file)?;
+      // Only an animation that ran past the screenshot needs to go back for it
+      if frames.is_past(first) {
+        frames.seek(first)?;
+      }
+    } else if sparse || index == 0 {
+      frames.seek(first)?;
+    }
+    let mut shots = Vec::with_capacity(spans.len());
+    for span in spans.iter() {
+      let times = candidate_times(*span, options.frame.candidates());
+      let selected;
+      let decoded = match analyzer.as_mut() {
+        None => frames.frame_after(times[0])?,
+        Some(analyzer) => {
+          selected = analyzer.select(&mut frames, &times)?;
+          selected.as_ref()
+        }
+      };
+      let Some(decoded) = decoded else { break; };
+      let mut rgb_frame = Video::empty();
+      match crop {
+        None => scaler.run(decoded, &mut rgb_frame)?,
+        Some(crop) => scaler.run(&crop_frame(decoded, &crop)?, &mut rgb_frame)?,
+      }
+      if let Some(graph) = burner.as_mut() {
+        rgb_frame = burn_in(graph, rgb_frame, span.0.half_way(&span.1))?;
+      }
+      shots.push(rgb_frame);
+    }
+    if shots.is_empty() { break; }
+    let snapshot_file = format!("{}/{}_{}", folder, prefix, dialogue.start.hms());
+    info!("Saving {}", snapshot_file);
+    save_screenshots(&options.image, &snapshot_file, &shots, spans.len(), options.contact_sheet)?;
+    if shots.len() < spans.len() { break; }
+  }
+  Ok(())
+}
+
+/// Equal parts of the dialogue, one for each screenshot.
+fn screenshot_spans(dialogue: &Dialogue, count: usize) -> Vec<(Time, Time)> {
+  let count = count.max(1) as i64;
+  let duration = (dialogue.end - dialogue.start).micros();
+  let at = |i: i64| dialogue.start + Time::from_micros(duration * i / count);
+  (0..count).map(|i| (at(i), at(i + 1))).collect()
+}
+
+/// Times spread evenly within the span, where a single candidate is the midpoint.
+fn candidate_times((start, end): (Time, Time), count: i64) -> Vec<Time> {
+  let duration = (end - start).micros();
+  (1..=count)
+    .map(|i| start + Time::from_micros(duration * i / (count + 1)))
+    .collect()
+}
+
+/// Saves a single screenshot as is, and `count` either numbered or tiled into a contact sheet.
+/// Shots missing at the end of the video are filled in with the last one, as the cards refer to
+/// all of them.
+fn save_screenshots(image: &ImageEncoder, filename: &str, shots: &[Video], count: usize, contact_sheet: bool)
+                    -> Result<()> {
+  let (width, height) = (shots[0].width(), shots[0].height());
+  let mut pixels = shots.iter().map(packed_rgb).collect::<Vec<_>>();
+  if let Some(last) = pixels.last().cloned() {
+    pixels.resize(count.max(pixels.len()), last);
+  }
+  if pixels.len() == 1 {
+    return image.save_rgb(filename, &pixels[0], width, height);
+  }
+  if contact_sheet {
+    let (sheet, sheet_width, sheet_height) = tile(&pixels, width, height);
+    return image.save_rgb(filename, &sheet, sheet_width, sheet_height);
+  }
+  for (index, pixels) in pixels.iter().enumerate() {
+    image.save_rgb(&format!("{}_{}", filename, index + 1), pixels, width, height)?;
+  }
+  Ok(())
+}
+
+/// Tiles RGB images of the same size left to right and top to bottom, with at least as many columns
+/// as rows, leaving any cells after the last image black.
+fn tile(images: &[Vec<u8>], width: u32, height: u32) -> (Vec<u8>, u32, u32) {
+  let rows = ((images.len() as f64).sqrt() as usize).max(1);
+  let columns = images.len().div_ceil(rows);
+  let row_bytes = width as usize * 3;
+  let sheet_row_bytes = row_bytes * columns;
+  let mut sheet = vec![0u8; sheet_row_bytes * height as usize * rows];
+  for (index, image) in images.iter().enumerate() {
+    let (row, column) = (index / columns, index % columns);
+    for (line, pixels) in image.chunks_exact(row_bytes).enumerate() {
+      let offset = (row * height as usize + line) * sheet_row_bytes + column * row_bytes;
+      sheet[offset..offset + row_bytes].copy_from_slice(pixels);
+    }
+  }
+  (sheet, width * columns as u32, height * rows as u32)
+}
+
+fn is_sparse(targets: &[(&Dialogue, Time)]) -> bool {
+  let (first, last) = (targets[0].1, targets[targets.len() - 1].1);
+  (last - first).micros() / targets.len() as i64 > SEEK_SPACING.micros()
+}
+
+/// Decoded frames of the best video stream, optionally deinterlaced.
+struct VideoFrames {
+  input: Input,
+  video: decoder::Video,
+  stream_index: usize,
+  time_base: Rational,
+  deinterlacer: Option<filter::Graph>,
+  interlaced: Video,
+  frame: Video,
+  current: Option<Time>,
+}
+
+impl VideoFrames {
+  fn open(video_file: &str, deinterlace: bool) -> Result<VideoFrames> {
+    let input = input(&video_file)?;
     let stream = input
       .streams()
       .best(Type::Video)
       .ok_or(Error::StreamNotFound)?;
+    let stream_index = stream.index();
+    let time_base = stream.time_base();
     let context = context::Context::from_parameters(stream.parameters())?;
-    let mut video = context.decoder().video()?;
-    let mut scaler = create_scaler(&video)?;
+    let video = context.decoder().video()?;
+    let deinterlacer = if deinterlace { Some(deinterlacer(&video, time_base)?) } else { None };
+    Ok(VideoFrames {
+      input,
+      video,
+      stream_index,
+      time_base,
+      deinterlacer,
+      interlaced: Video::empty(),
+      frame: Video::empty(),
+      current: None,
+    })
+  }
 
-    let mut dialogues = subtitles.iter();
-    let mut dialogue = dialogues.next().unwrap();
-    let mut half = dialogue.start.half_way(&dialogue.end);
+  /// Seeks to the keyframe at or before `time`, so decoding forward reaches it.
+  fn seek(&mut self, time: Time) -> Result<()> {
+    self.input.seek(time.micros(), ..time.micros())?;
+    self.video.flush();
+    if self.deinterlacer.is_some() {
+      self.deinterlacer = Some(deinterlacer(&self.video, self.time_base)?);
+    }
+    self.current = None;
+    Ok(())
+  }
 
-    let (start, end) = sample_range(sample);
+  fn is_past(&self, time: Time) -> bool {
+    self.current.is_some_and(|current| current > time)
+  }
 
-    let stream_index = stream.index();
-    for (stream, packet) in input.packets() {
-      if stream.index() != stream_index { continue; }
-      video.send_packet(&packet)?;
-      let mut decoded = Video::empty();
-      while video.receive_frame(&mut decoded).is_ok() {
-        let timestamp = decoded.timestamp().unwrap();
-        let timestamp = Time::from_nanos(timestamp as u64);
-
-        if timestamp.milliseconds() > end { return Ok(()); }
-
-        if timestamp.milliseconds() > half.milliseconds() {
-          if timestamp.milliseconds() > start {
-            let snapshot_file = format!("{}/{}_{}", folder, prefix, dialogue.start.hms());
-            info!("Saving {}", snapshot_file);
-            let mut rgb_frame = Video::empty();
-            scaler.run(&decoded, &mut rgb_frame)?;
-            save_snapshot(&rgb_frame, snapshot_file.clone()).unwrap();
-          }
-          match dialogues.next() {
-            None => { return Ok(()); }
-            Some(next) => {
-              dialogue = next;
-              half = dialogue.start.half_way(&dialogue.end);
-            }
-          }
+  /// Decodes forward to the first frame after `time`, or `None` at the end of the video. The
+  /// current frame is reused when it is already past `time`, so dialogue close together can share
+  /// a frame whether it was reached by seeking or decoding.
+  fn frame_after(&mut self, time: Time) -> Result<Option<&Video>> {
+    if self.is_past(time) {
+      return Ok(Some(&self.frame));
+    }
+    loop {
+      while self.receive_frame()? {
+        let Some(timestamp) = self.frame.timestamp() else { continue; };
+        let timestamp = Time::from_timestamp(timestamp, self.time_base);
+        self.current = Some(timestamp);
+        if timestamp > time {
+          return Ok(Some(&self.frame));
         }
       }
+      let stream_index = self.stream_index;
+      match self.input.packets().find(|(stream, _)| stream.index() == stream_index) {
+        Some((_, packet)) => self.video.send_packet(&packet)?,
+        None => return Ok(None),
+      }
+    }
+  }
+
+  /// Receives the next frame, passing it through the deinterlacer when there is one. Returns false
+  /// when the decoder needs another packet.
+  fn receive_frame(&mut self) -> Result<bool> {
+    let Some(graph) = self.deinterlacer.as_mut() else {
+      return Ok(self.video.receive_frame(&mut self.frame).is_ok());
+    };
+    loop {
+      if graph.get("out").unwrap().sink().frame(&mut self.frame).is_ok() {
+        return Ok(true);
+      }
+      if self.video.receive_frame(&mut self.interlaced).is_err() {
+        return Ok(false);
+      }
+      graph.get("in").unwrap().source().add(&self.interlaced)?;
     }
   }
-  info!("Done!");
-  Ok(())
 }
 
-fn create_scaler(video: &ffmpeg_next::decoder::Video) -> Result<Context> {
-  let scaler = Context::get(
+/// Filter graph that deinterlaces the frames flagged as interlaced, one output frame per input.
+fn deinterlacer(video: &decoder::Video, time_base: Rational) -> Result<filter::Graph> {
+  let size = (video.width(), video.height());
+  filter_graph(video.format(), size, time_base, video.aspect_ratio(),
+               "yadif=mode=send_frame:parity=auto:deint=interlaced")
+}
+
+/// Filter graph that draws the subtitles onto scaled screenshots, timed in microseconds.
+fn burner(output: &Definition, subtitles: &str) -> Result<filter::Graph> {
+  let spec = format!("ass=filename={}", escape_filter_value(subtitles));
+  filter_graph(output.format, (output.width, output.height), MICROSECONDS, Rational(1, 1), &spec)
+}
+
+/// Draws the subtitles showing at `time` onto the frame.
+fn burn_in(graph: &mut filter::Graph, mut frame: Video, time: Time) -> Result<Video> {
+  frame.set_pts(Some(time.micros()));
+  graph.get("in").unwrap().source().add(&frame)?;
+  let mut burned = Video::empty();
+  graph.get("out").unwrap().sink().frame(&mut burned)?;
+  Ok(burned)
+}
+
+fn filter_graph(format: Pixel, size: (u32, u32), time_base: Rational, aspect: Rational, spec: &str)
+                -> Result<filter::Graph> {
+  let aspect = if aspect.numerator() > 0 && aspect.denominator() > 0 { aspect } else { Rational(1, 1) };
+  let args = format!(
+    "video_size={}x{}:pix_fmt={}:time_base={}/{}:pixel_aspect={}/{}",
+    size.0,
+    size.1,
+    AVPixelFormat::from(format) as i32,
+    time_base.numerator(),
+    time_base.denominator(),
+    aspect.numerator(),
+    aspect.denominator(),
+  );
+  let mut graph = filter::Graph::new();
+  graph.add(&filter::find("buffer").ok_or(Error::FilterNotFound)?, "in", &args)?;
+  graph.add(&filter::find("buffersink").ok_or(Error::FilterNotFound)?, "out", "")?;
+  graph.output("in", 0)?.input("out", 0)?.parse(spec)?;
+  graph.validate()?;
+  Ok(graph)
+}
+
+/// Escapes a filter option value, and then again for the filter graph description it is part of.
+fn escape_filter_value(value: &str) -> String {
+  let escape = |value: &str, special: &str| value.chars()
+    .fold(String::new(), |mut escaped, c| {
+      if special.contains(c) {
+        escaped.push('\\');
+      }
+      escaped.push(c);
+      escaped
+    });
+  escape(&escape(value, "\\':"), "\\'[],;")
+}
+
+/// Finds the largest area without black bars in a few frames spread over the dialogue.
+fn detect_crop(video_file: &str, targets: &[(&Dialogue, Time)]) -> Result<Option<CropRect>> {
+  let mut frames = VideoFrames::open(video_file, false)?;
+  let mut gray = Context::get(
+    frames.video.format(),
+    frames.video.width(),
+    frames.video.height(),
+    Pixel::GRAY8,
+    frames.video.width(),
+    frames.video.height(),
+    Flags::BILINEAR,
+  )?;
+
+  let step = targets.len().div_ceil(CROP_SAMPLES);
+  let mut crop: Option<CropRect> = None;
+  for (_, time) in targets.iter().step_by(step) {
+    frames.seek(*time)?;
+    let Some(decoded) = frames.frame_after(*time)? else { break; };
+    let mut luma = Video::empty();
+    gray.run(decoded, &mut luma)?;
+    let Some(bounds) = content_bounds(luma.data(0), luma.stride(0), luma.width(), luma.height()) else {
+      continue;
+    };
+    crop = Some(match crop {
+      None => bounds,
+      Some(crop) => union(&crop, &bounds),
+    });
+  }
+  let crop = crop.filter(|crop| crop.width != frames.video.width() || crop.height != frames.video.height());
+  info!("Detected crop {:?}", crop);
+  Ok(crop)
+}
+
+/// Bounds of the rows and columns that are brighter than black, with even offsets and sizes for
+/// subsampled chroma.
+fn content_bounds(luma: &[u8], stride: usize, width: u32, height: u32) -> Option<CropRect> {
+  let (width, height) = (width as usize, height as usize);
+  let bright_row = |y: &usize| {
+    luma[y * stride..y * stride + width].iter().map(|p| *p as usize).sum::<usize>() > BLACK_LIMIT * width
+  };
+  let bright_column = |x: &usize| {
+    (0..height).map(|y| luma[y * stride + x] as usize).sum::<usize>() > BLACK_LIMIT * height
+  };
+  let top = (0..height).find(bright_row)? & !1;
+  let bottom = (0..height).rev().find(bright_row)? + 1;
+  let left = (0..width).find(bright_column)? & !1;
+  let right = (0..width).rev().find(bright_column)? + 1;
+  let even = |size: usize, limit: usize| (size.div_ceil(2) * 2).min(limit);
+  Some(CropRect {
+    width: even(right - left, width - left) as u32,
+    height: even(bottom - top, height - top) as u32,
+    x: left as u32,
+    y: top as u32,
+  })
+}
+
+fn union(one: &CropRect, other: &CropRect) -> CropRect {
+  let x = one.x.min(other.x);
+  let y = one.y.min(other.y);
+  CropRect {
+    width: (one.x + one.width).max(other.x + other.width) - x,
+    height: (one.y + one.height).max(other.y + other.height) - y,
+    x,
+    y,
+  }
+}
+
+/// Samples frames across the dialogue at the animation frame rate.
+struct Animator {
+  crop: Option<CropRect>,
+  scaler: Context,
+  options: AnimationOptions,
+}
+
+impl Animator {
+  fn new(video: &decoder::Video, crop: Option<CropRect>, options: &AnimationOptions, algorithm: ScalerAlgorithm)
+         -> Result<Animator> {
+    let scaler = create_scaler(video, crop, Some(options.width), None, algorithm)?;
+    Ok(Animator { crop, scaler, options: *options })
+  }
+
+  fn save(&mut self, frames: &mut VideoFrames, dialogue: &Dialogue, filename: &str) -> Result<()> {
+    let step = Time::from_micros(1_000_000 / self.options.encoder.fps.max(1) as i64);
+    let end = dialogue.end.min(dialogue.start + self.options.max_length);
+    let mut images = vec![];
+    let mut time = dialogue.start;
+    while time < end {
+      let Some(decoded) = frames.frame_after(time)? else { break; };
+      let mut rgb_frame = Video::empty();
+      match self.crop {
+        None => self.scaler.run(decoded, &mut rgb_frame)?,
+        Some(crop) => self.scaler.run(&crop_frame(decoded, &crop)?, &mut rgb_frame)?,
+      }
+      images.push(packed_rgb(&rgb_frame));
+      time = time + step;
+    }
+    if images.is_empty() {
+      return Ok(());
+    }
+    info!("Saving {} with {} frames", filename, images.len());
+    let output = self.scaler.output();
+    self.options.encoder.save_rgb(filename, &images, output.width, output.height)
+  }
+}
+
+#[derive(Debug, PartialEq)]
+struct FrameStats {
+  /// Mean luma
+  brightness: f64,
+  /// Variance of the Laplacian, which is low for blurred frames
+  sharpness: f64,
+  /// Mean absolute luma difference from the previous candidate
+  change: f64,
+}
+
+/// Compares candidate frames on a small grayscale copy.
+struct FrameAnalyzer {
+  crop: Option<CropRect>,
+  gray: Context,
+}
+
+impl FrameAnalyzer {
+  fn new(video: &decoder::Video, crop: Option<CropRect>) -> Result<FrameAnalyzer> {
+    let source = crop.map_or((video.width(), video.height()), |crop| (crop.width, crop.height));
+    let (width, height) = output_size(source, Some(ANALYSIS_WIDTH.min(source.0)), None);
+    let gray = Context::get(video.format(), source.0, source.1, Pixel::GRAY8, width, height, Flags::AREA)?;
+    Ok(FrameAnalyzer { crop, gray })
+  }
+
+  /// Decodes a frame after each of `times` and keeps the best one, or `None` at the end of the video.
+  fn select(&mut self, frames: &mut VideoFrames, times: &[Time]) -> Result<Option<Video>> {
+    let mut candidates: Vec<Video> = vec![];
+    let mut stats: Vec<FrameStats> = vec![];
+    let mut previous: Option<Video> = None;
+    for time in times {
+      let Some(decoded) = frames.frame_after(*time)? else { break; };
+      let mut luma = Video::empty();
+      match self.crop {
+        None => self.gray.run(decoded, &mut luma)?,
+        Some(crop) => self.gray.run(&crop_frame(decoded, &crop)?, &mut luma)?,
+      }
+      stats.push(frame_stats(&luma, previous.as_ref()));
+      candidates.push(reference_frame(decoded)?);
+      previous = Some(luma);
+    }
+    if candidates.is_empty() {
+      return Ok(None);
+    }
+    let index = select_candidate(&stats);
+    debug!("Selected candidate {} of {}: {:?}", index, stats.len(), stats[index]);
+    Ok(Some(candidates.swap_remove(index)))
+  }
+}
+
+fn frame_stats(luma: &Video, previous: Option<&Video>) -> FrameStats {
+  let (width, height, stride) = (luma.width() as usize, luma.height() as usize, luma.stride(0));
+  let previous = previous.map(|previous| previous.data(0));
+  luma_stats(luma.data(0), previous, stride, width, height)
+}
+
+fn luma_stats(luma: &[u8], previous: Option<&[u8]>, stride: usize, width: usize, height: usize) -> FrameStats {
+  let pixel = |x: usize, y: usize| luma[y * stride + x] as f64;
+  let pixels = (width * height) as f64;
+  let brightness = (0..height).flat_map(|y| (0..width).map(move |x| (x, y)))
+    .map(|(x, y)| pixel(x, y))
+    .sum::<f64>() / pixels;
+
+  let laplacian = (1..height.saturating_sub(1))
+    .flat_map(|y| (1..width.saturating_sub(1)).map(move |x| (x, y)))
+    .map(|(x, y)| pixel(x - 1, y) + pixel(x + 1, y) + pixel(x, y - 1) + pixel(x, y + 1) - 4.0 * pixel(x, y))
+    .collect::<Vec<_>>();
+  let mean = laplacian.iter().sum::<f64>() / laplacian.len().max(1) as f64;
+  let sharpness = laplacian.iter().map(|l| (l - mean).powi(2)).sum::<f64>() / laplacian.len().max(1) as f64;
+
+  let change = previous.map_or(0.0, |previous| {
+    (0..height).flat_map(|y| (0..width).map(move |x| y * stride + x))
+      .map(|i| (luma[i] as f64 - previous[i] as f64).abs())
+      .sum::<f64>() / pixels
+  });
+  FrameStats { brightness, sharpness, change }
+}
+
+/// Index of the sharpest frame that is not black, among the candidates in the same scene as the
+/// middle one. Falls back to the middle when every frame in that scene is black.
+fn select_candidate(stats: &[FrameStats]) -> usize {
+  let middle = stats.len() / 2;
+  let first = (1..=middle).rev()
+    .find(|i| stats[*i].change > SCENE_CHANGE)
+    .unwrap_or(0);
+  let last = (middle + 1..stats.len())
+    .find(|i| stats[*i].change > SCENE_CHANGE)
+    .unwrap_or(stats.len());
+  (first..last)
+    .filter(|i| stats[*i].brightness > BLACK_LIMIT as f64)
+    .max_by(|a, b| stats[*a].sharpness.total_cmp(&stats[*b].sharpness))
+    .unwrap_or(middle)
+}
+
+/// Another reference to the frame data, which stays valid while decoding continues.
+fn reference_frame(frame: &Video) -> Result<Video> {
+  let mut reference = Video::empty();
+  unsafe {
+    match av_frame_ref(reference.as_mut_ptr(), frame.as_ptr()) {
+      e if e < 0 => Err(Error::from(e).into()),
+      _ => Ok(reference),
+    }
+  }
+}
+
+/// References the frame data, narrowed to the crop area.
+fn crop_frame(frame: &Video, crop: &CropRect) -> Result<Video> {
+  let mut cropped = reference_frame(frame)?;
+  unsafe {
+    let ptr = cropped.as_mut_ptr();
+    (*ptr).crop_left = crop.x as _;
+    (*ptr).crop_top = crop.y as _;
+    (*ptr).crop_right = frame.width().saturating_sub(crop.x + crop.width) as _;
+    (*ptr).crop_bottom = frame.height().saturating_sub(crop.y + crop.height) as _;
+    match av_frame_apply_cropping(ptr, CROP_UNALIGNED) {
+      e if e < 0 => Err(Error::from(e).into()),
+      _ => Ok(cropped),
+    }
+  }
+}
+
+/// `AV_FRAME_CROP_UNALIGNED`, to crop exactly instead of to the nearest aligned offset.
+const CROP_UNALIGNED: i32 = 1;
+
+/// Output size that fits `width` and `height`, keeping the aspect ratio of the source when only
+/// one is given. Sizes are even for the encoders that need it.
+pub(crate) fn output_size(source: (u32, u32), width: Option<u32>, height: Option<u32>) -> (u32, u32) {
+  let (source_width, source_height) = (source.0 as f64, source.1 as f64);
+  let scale = match (width, height) {
+    (None, None) => return source,
+    (Some(width), None) => width as f64 / source_width,
+    (None, Some(height)) => height as f64 / source_height,
+    (Some(width), Some(height)) => (width as f64 / source_width).min(height as f64 / source_height),
+  };
+  let even = |size: f64| ((size / 2.0).round() as u32 * 2).max(2);
+  (even(source_width * scale), even(source_height * scale))
+}
+
+/// Size with anamorphic pixels stretched to square ones like a player would show it, with an even width.
+pub(crate) fn display_size(source: (u32, u32), aspect: Rational) -> (u32, u32) {
+  if aspect.numerator() <= 0 || aspect.denominator() <= 0 || aspect.numerator() == aspect.denominator() {
+    return source;
+  }
+  let width = source.0 as f64 * aspect.numerator() as f64 / aspect.denominator() as f64;
+  ((width / 2.0).round() as u32 * 2, source.1)
+}
+
+fn create_scaler(video: &decoder::Video, crop: Option<CropRect>, width: Option<u32>, height: Option<u32>,
+                 algorithm: ScalerAlgorithm) -> Result<Context> {
+  let source = crop.map_or((video.width(), video.height()), |crop| (crop.width, crop.height));
+  let (width, height) = output_size(display_size(source, video.aspect_ratio()), width, height);
+  let mut scaler = Context::get(
     video.format(),
-    video.width(),
-    video.height(),
+    source.0,
+    source.1,
     Pixel::RGB24,
-    video.width(),
-    video.height(),
-    Flags::BILINEAR,
+    width,
+    height,
+    algorithm.flags(),
   )?;
+  set_color_details(&mut scaler, video, None)?;
   Ok(scaler)
 }
 
-fn save_snapshot(frame: &Video, filename: String) -> Result<()> {
-  let encoder = Encoder::new_file(format!("{}.jpg", filename), 65)?;
-  encoder.encode(
-    frame.data(0),
-    frame.width() as u16,
-    frame.height() as u16,
-    ColorType::Rgb)?;
-  Ok(())
+/// Converts with the YCbCr matrix and range of the source to full range RGB, or to limited range
+/// YCbCr of `yuv_height` with the matrix players assume for untagged video of that height.
+/// Without color information, SD sources are assumed to be BT.601 and HD sources BT.709.
+pub(crate) fn set_color_details(scaler: &mut Context, video: &decoder::Video, yuv_height: Option<u32>) -> Result<()> {
+  let matrix = match video.color_space() {
+    Space::RGB => return Ok(()),
+    Space::BT709 => SWS_CS_ITU709,
+    Space::FCC => SWS_CS_FCC,
+    Space::BT470BG | Space::SMPTE170M => SWS_CS_ITU601,
+    Space::SMPTE240M => SWS_CS_SMPTE240M,
+    Space::BT2020NCL | Space::BT2020CL => SWS_CS_BT2020,
+    _ => default_matrix(video.height()),
+  };
+  let full_range = video.color_range() == Range::JPEG
+    || matches!(video.format(), Pixel::YUVJ420P | Pixel::YUVJ422P | Pixel::YUVJ440P | Pixel::YUVJ444P);
+  let (output, output_full_range) = match yuv_height {
+    Some(height) => (default_matrix(height), false),
+    None => (SWS_CS_DEFAULT, true),
+  };
+  debug!("Converting {:?} {:?} with matrix {}, full range {}", video.color_space(), video.color_range(), matrix,
+         full_range);
+  unsafe {
+    match sws_setColorspaceDetails(scaler.as_mut_ptr(), sws_getCoefficients(matrix), full_range as i32,
+                                   sws_getCoefficients(output), output_full_range as i32, 0, 1 << 16, 1 << 16) {
+      e if e < 0 => Err(Error::from(e).into()),
+      _ => Ok(()),
+    }
+  }
+}
+
+fn default_matrix(height: u32) -> i32 {
+  if height >= 720 { SWS_CS_ITU709 } else { SWS_CS_ITU601 }
+}
+
+/// RGB24 pixels without the padding at the end of each line.
+fn packed_rgb(frame: &Video) -> Vec<u8> {
+  let row = frame.width() as usize * 3;
+  frame.data(0)
+    .chunks(frame.stride(0))
+    .take(frame.height() as usize)
+    .flat_map(|line| &line[..row])
+    .copied()
+    .collect()
 }
 
 pub fn snapshot(video: &Path, time: Time, output: String) -> std::io::Result<ExitStatus> {
@@ -121,28 +839,211 @@ pub fn audio(video: &Path, start: &Time, end: &Time, output: String) -> std::io:
 
 #[cfg(test)]
 mod tests {
-  use crate::offset_subtitle_file;
+  use crate::{offset_subtitle_file, SubtitleFormat, write_subtitle_file};
+  use crate::image::AnimationFormat;
 
   use super::*;
 
   #[test]
   fn it_extracts_image() {
-    let mut dialogue = offset_subtitle_file("tests/totoro.ja.srt", None).unwrap();
+    let mut dialogue = offset_subtitle_file("tests/totoro.ja.srt", &None).unwrap();
     let one = dialogue.remove(50);
     let dialogue = vec![one];
-    extract_screenshots("totoro.mkv", "target", "totoro", &dialogue, &None).unwrap();
+    extract_screenshots("totoro.mkv", "target", "totoro", &dialogue, &None, &ScreenshotOptions::default()).unwrap();
   }
 
   #[test]
   fn it_extracts_images() {
-    let dialogue = offset_subtitle_file("tests/totoro.ja.srt", None).unwrap();
-    extract_screenshots("totoro.mkv", "target", "totoro", &dialogue, &None).unwrap();
+    let dialogue = offset_subtitle_file("tests/totoro.ja.srt", &None).unwrap();
+    extract_screenshots("totoro.mkv", "target", "totoro", &dialogue, &None, &ScreenshotOptions::default()).unwrap();
+  }
+
+  #[test]
+  fn it_seeks_sparse_dialogue() {
+    let dialogue = offset_subtitle_file("tests/totoro.ja.srt", &None).unwrap();
+    let every_tenth = dialogue.iter().step_by(10).collect::<Vec<_>>();
+    let targets = every_tenth.iter().map(|d| (*d, d.start.half_way(&d.end))).collect::<Vec<_>>();
+    assert!(is_sparse(&targets));
+    let targets = dialogue.iter().map(|d| (d, d.start.half_way(&d.end))).collect::<Vec<_>>();
+    assert!(!is_sparse(&targets));
+  }
+
+  #[test]
+  fn it_extracts_sample() {
+    let dialogue = offset_subtitle_file("tests/totoro.ja.srt", &None).unwrap();
+    extract_screenshots("totoro.mkv", "target", "totoro", &dialogue, &Some(20), &ScreenshotOptions::default()).unwrap();
+  }
+
+  #[test]
+  fn it_extracts_images_in_parallel() {
+    let dialogue = offset_subtitle_file("tests/totoro.ja.srt", &None).unwrap();
+    let options = ScreenshotOptions { jobs: 8, ..ScreenshotOptions::default() };
+    extract_screenshots("totoro.mkv", "target", "totoro", &dialogue, &None, &options).unwrap();
+  }
+
+  #[test]
+  fn it_scales_preserving_aspect() {
+    assert_eq!((1920, 1080), output_size((1920, 1080), None, None));
+    assert_eq!((640, 360), output_size((1920, 1080), Some(640), None));
+    assert_eq!((640, 360), output_size((1920, 1080), None, Some(360)));
+    assert_eq!((480, 270), output_size((1920, 1080), Some(480), Some(480)));
+    assert_eq!((640, 266), output_size((1920, 800), Some(640), None));
+  }
+
+  #[test]
+  fn it_stretches_anamorphic_pixels() {
+    assert_eq!((854, 480), display_size((720, 480), Rational(32, 27)));
+    assert_eq!((640, 480), display_size((720, 480), Rational(8, 9)));
+    assert_eq!((1920, 1080), display_size((1920, 1080), Rational(1, 1)));
+    assert_eq!((720, 480), display_size((720, 480), Rational(0, 1)));
+    assert_eq!((640, 360), output_size(display_size((720, 480), Rational(32, 27)), None, Some(360)));
+  }
+
+  #[test]
+  fn it_escapes_filter_paths() {
+    assert_eq!("target/ichigo-01.ass", escape_filter_value("target/ichigo-01.ass"));
+    assert_eq!("C\\\\:\\\\\\\\subs\\\\\\\\it\\\\\\\'s.ass", escape_filter_value("C:\\subs\\it's.ass"));
+    assert_eq!("subs\\[1\\].ass", escape_filter_value("subs[1].ass"));
+  }
+
+  #[test]
+  fn it_parses_crop() {
+    assert_eq!(Ok(Crop::Auto), "auto".parse());
+    assert_eq!(Ok(Crop::Rect(CropRect { width: 1920, height: 800, x: 0, y: 140 })), "1920:800:0:140".parse());
+    assert!("1920x800".parse::<Crop>().is_err());
+  }
+
+  #[test]
+  fn it_checks_crop_fits_frame() {
+    assert!(CropRect { width: 1920, height: 800, x: 0, y: 140 }.fits(1920, 1080));
+    assert!(!CropRect { width: 1920, height: 800, x: 0, y: 300 }.fits(1920, 1080));
+    assert!(!CropRect { width: 1920, height: 800, x: 1, y: 0 }.fits(1920, 1080));
+    assert!(!CropRect { width: 0, height: 800, x: 0, y: 0 }.fits(1920, 1080));
+    assert!(!CropRect { width: 1, height: 1, x: u32::MAX, y: 0 }.fits(1920, 1080));
+  }
+
+  #[test]
+  fn it_detects_black_bars() {
+    let (width, height) = (16, 12);
+    let mut luma = vec![16u8; width * height];
+    for y in 3..9 {
+      for x in 1..15 {
+        luma[y * width + x] = 128;
+      }
+    }
+    let crop = content_bounds(&luma, width, width as u32, height as u32).unwrap();
+    assert_eq!(CropRect { width: 16, height: 8, x: 0, y: 2 }, crop);
+  }
+
+  #[test]
+  fn it_spreads_candidates_over_dialogue() {
+    let dialogue = Dialogue { start: Time::new(0, 1, 0, 0), end: Time::new(0, 1, 6, 0), text: String::new(), image: None };
+    let span = (dialogue.start, dialogue.end);
+    assert_eq!(vec![dialogue.start.half_way(&dialogue.end)], candidate_times(span, 1));
+    assert_eq!(vec![Time::new(0, 1, 1, 0), Time::new(0, 1, 2, 0), Time::new(0, 1, 3, 0), Time::new(0, 1, 4, 0),
+                    Time::new(0, 1, 5, 0)], candidate_times(span, 5));
+  }
+
+  #[test]
+  fn it_splits_dialogue_for_each_screenshot() {
+    let dialogue = Dialogue { start: Time::new(0, 1, 0, 0), end: Time::new(0, 1, 6, 0), text: String::new(), image: None };
+    assert_eq!(vec![(dialogue.start, dialogue.end)], screenshot_spans(&dialogue, 1));
+    let spans = screenshot_spans(&dialogue, 3);
+    assert_eq!(vec![(Time::new(0, 1, 0, 0), Time::new(0, 1, 2, 0)), (Time::new(0, 1, 2, 0), Time::new(0, 1, 4, 0)),
+                    (Time::new(0, 1, 4, 0), Time::new(0, 1, 6, 0))], spans);
+    let middles = spans.iter().map(|span| candidate_times(*span, 1)[0]).collect::<Vec<_>>();
+    assert_eq!(vec![Time::new(0, 1, 1, 0), Time::new(0, 1, 3, 0), Time::new(0, 1, 5, 0)], middles);
+  }
+
+  #[test]
+  fn it_tiles_contact_sheet() {
+    let images = (1..=3u8).map(|shade| vec![shade; 6]).collect::<Vec<_>>();
+    let (sheet, width, height) = tile(&images, 2, 1);
+    assert_eq!((6, 1), (width, height));
+    assert_eq!(vec![1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 3, 3, 3, 3, 3, 3], sheet);
+    let images = (1..=5u8).map(|shade| vec![shade; 3]).collect::<Vec<_>>();
+    let (sheet, width, height) = tile(&images, 1, 1);
+    assert_eq!((3, 2), (width, height));
+    assert_eq!(vec![1, 1, 1, 2, 2, 2, 3, 3, 3, 4, 4, 4, 5, 5, 5, 0, 0, 0], sheet);
+  }
+
+  #[test]
+  fn it_measures_sharpness() {
+    let (width, height) = (8, 8);
+    let flat = vec![128u8; width * height];
+    let checkered = (0..width * height).map(|i| if (i % width + i / width) % 2 == 0 { 64 } else { 192 }).collect::<Vec<_>>();
+    let stats = luma_stats(&flat, None, width, width, height);
+    assert_eq!(FrameStats { brightness: 128.0, sharpness: 0.0, change: 0.0 }, stats);
+    let stats = luma_stats(&checkered, Some(&flat), width, width, height);
+    assert_eq!(128.0, stats.brightness);
+    assert!(stats.sharpness > 0.0);
+    assert_eq!(64.0, stats.change);
+  }
+
+  #[test]
+  fn it_selects_sharpest_frame_in_scene() {
+    let stats = |brightness: f64, sharpness: f64, change: f64| FrameStats { brightness, sharpness, change };
+    let sharp_before_cut = [stats(100.0, 90.0, 0.0), stats(100.0, 10.0, 50.0), stats(100.0, 20.0, 2.0),
+                            stats(100.0, 30.0, 2.0), stats(100.0, 5.0, 2.0)];
+    assert_eq!(3, select_candidate(&sharp_before_cut));
+    let fade_to_black = [stats(60.0, 10.0, 0.0), stats(40.0, 20.0, 2.0), stats(30.0, 5.0, 2.0),
+                         stats(10.0, 80.0, 2.0), stats(2.0, 90.0, 2.0)];
+    assert_eq!(1, select_candidate(&fade_to_black));
+    let black = [stats(2.0, 1.0, 0.0), stats(2.0, 1.0, 0.0), stats(2.0, 3.0, 0.0)];
+    assert_eq!(1, select_candidate(&black));
+  }
+
+  #[test]
+  fn it_extracts_sharpest_images() {
+    let dialogue = offset_subtitle_file("tests/totoro.ja.srt", &None).unwrap();
+    let options = ScreenshotOptions { frame: FrameSelection::Sharpest, ..ScreenshotOptions::default() };
+    extract_screenshots("totoro.mkv", "target", "totoro", &dialogue, &Some(20), &options).unwrap();
+  }
+
+  #[test]
+  fn it_extracts_deinterlaced_images() {
+    let dialogue = offset_subtitle_file("tests/ichigo-01_jp.ass", &None).unwrap();
+    let options = ScreenshotOptions { deinterlace: true, ..ScreenshotOptions::default() };
+    extract_screenshots("ichigo-01.mkv", "target", "ichigo-01", &dialogue, &Some(2), &options).unwrap();
+  }
+
+  #[test]
+  fn it_burns_in_subtitles() {
+    let dialogue = offset_subtitle_file("tests/totoro.ja.srt", &None).unwrap();
+    write_subtitle_file("target/totoro-burn.ass", &dialogue, SubtitleFormat::Ass).unwrap();
+    let options = ScreenshotOptions { burn_in: Some("target/totoro-burn.ass".into()), ..ScreenshotOptions::default() };
+    extract_screenshots("totoro.mkv", "target", "totoro", &dialogue, &Some(20), &options).unwrap();
+  }
+
+  #[test]
+  fn it_extracts_animations() {
+    let dialogue = offset_subtitle_file("tests/totoro.ja.srt", &None).unwrap();
+    let encoder = AnimationEncoder { format: AnimationFormat::Gif, quality: 60, fps: 8, max_bytes: 0 };
+    let animation = AnimationOptions { encoder, width: 240, max_length: Time::from_millis(4000) };
+    let options = ScreenshotOptions { animation: Some(animation), ..ScreenshotOptions::default() };
+    extract_screenshots("totoro.mkv", "target", "totoro", &dialogue, &Some(20), &options).unwrap();
+  }
+
+  #[test]
+  fn it_extracts_contact_sheets() {
+    let dialogue = offset_subtitle_file("tests/totoro.ja.srt", &None).unwrap();
+    let options = ScreenshotOptions { screenshots: 3, width: Some(320), ..ScreenshotOptions::default() };
+    extract_screenshots("totoro.mkv", "target", "totoro", &dialogue, &Some(20), &options).unwrap();
+    let options = ScreenshotOptions { contact_sheet: true, ..options };
+    extract_screenshots("totoro.mkv", "target", "totoro-sheet", &dialogue, &Some(20), &options).unwrap();
+  }
+
+  #[test]
+  fn it_extracts_scaled_images() {
+    let dialogue = offset_subtitle_file("tests/totoro.ja.srt", &None).unwrap();
+    let options = ScreenshotOptions { width: Some(640), crop: Some(Crop::Auto), ..ScreenshotOptions::default() };
+    extract_screenshots("totoro.mkv", "target", "totoro", &dialogue, &Some(20), &options).unwrap();
   }
 
   #[test]
   fn it_runs_ffmpeg() -> Result<()> {
-    let start = Time { hour: 0, min: 1, sec: 39, mil: 620 };
-    let end = Time { hour: 0, min: 1, sec: 41, mil: 620 };
+    let start = Time::new(0, 1, 39, 620);
+    let end = Time::new(0, 1, 41, 620);
     let half = start.half_way(&end);
     let video = "ichigo-01.mkv";
 
@@ -0,0 +1,226 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use clap::ValueEnum;
+use jpeg_encoder::{ColorType, Encoder};
+use log::debug;
+use ravif::{Img, RGB8};
+use webp::{AnimEncoder, AnimFrame, WebPConfig};
+
+use crate::Result;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum ImageFormat {
+  #[default]
+  Jpeg,
+  /// Lossless
+  Png,
+  /// Lossless
+  Webp,
+  Avif,
+}
+
+impl ImageFormat {
+  pub fn extension(&self) -> &'static str {
+    match self {
+      ImageFormat::Jpeg => "jpg",
+      ImageFormat::Png => "png",
+      ImageFormat::Webp => "webp",
+      ImageFormat::Avif => "avif",
+    }
+  }
+
+  pub fn mime(&self) -> &'static str {
+    match self {
+      ImageFormat::Jpeg => "image/jpeg",
+      ImageFormat::Png => "image/png",
+      ImageFormat::Webp => "image/webp",
+      ImageFormat::Avif => "image/avif",
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ImageEncoder {
+  pub format: ImageFormat,
+  /// Quality from 1 to 100 for the lossy formats
+  pub quality: u8,
+}
+
+impl Default for ImageEncoder {
+  fn default() -> Self {
+    ImageEncoder { format: ImageFormat::Jpeg, quality: 65 }
+  }
+}
+
+impl ImageEncoder {
+  /// Saves packed RGB pixels to `filename` with the extension of the format appended.
+  pub fn save_rgb(&self, filename: &str, pixels: &[u8], width: u32, height: u32) -> Result<()> {
+    let filename = format!("{}.{}", filename, self.format.extension());
+    match self.format {
+      ImageFormat::Jpeg => {
+        let encoder = Encoder::new_file(filename, self.quality.clamp(1, 100))?;
+        encoder.encode(pixels, width as u16, height as u16, ColorType::Rgb)?;
+      }
+      ImageFormat::Png => {
+        let writer = BufWriter::new(File::create(filename)?);
+        let mut encoder = png::Encoder::new(writer, width, height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(pixels)?;
+      }
+      ImageFormat::Webp => {
+        let encoded = webp::Encoder::from_rgb(pixels, width, height).encode_lossless();
+        File::create(filename)?.write_all(&encoded)?;
+      }
+      ImageFormat::Avif => {
+        let pixels = pixels.chunks_exact(3)
+          .map(|p| RGB8::new(p[0], p[1], p[2]))
+          .collect::<Vec<_>>();
+        let encoded = ravif::Encoder::new()
+          .with_quality(self.quality.clamp(1, 100) as f32)
+          .with_speed(8)
+          .encode_rgb(Img::new(&pixels[..], width as usize, height as usize))?;
+        File::create(filename)?.write_all(&encoded.avif_file)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum AnimationFormat {
+  Gif,
+  Webp,
+}
+
+impl AnimationFormat {
+  pub fn extension(&self) -> &'static str {
+    match self {
+      AnimationFormat::Gif => "gif",
+      AnimationFormat::Webp => "webp",
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AnimationEncoder {
+  pub format: AnimationFormat,
+  /// Quality from 1 to 100 for WebP
+  pub quality: u8,
+  pub fps: u32,
+  /// Largest file in bytes, or 0 for no limit
+  pub max_bytes: usize,
+}
+
+impl AnimationEncoder {
+  /// Saves frames of packed RGB pixels, shown `fps` times a second and looping forever, to
+  /// `filename` with the extension of the format appended. Larger files than `max_bytes` keep
+  /// every other frame for twice as long, down to a single frame.
+  pub fn save_rgb(&self, filename: &str, frames: &[Vec<u8>], width: u32, height: u32) -> Result<()> {
+    let filename = format!("{}.{}", filename, self.format.extension());
+    let mut frames = frames.iter().collect::<Vec<_>>();
+    let mut delay_ms = 1000 / self.fps.max(1);
+    let mut encoded = self.encode(&frames, width, height, delay_ms)?;
+    while self.max_bytes > 0 && encoded.len() > self.max_bytes && frames.len() > 1 {
+      debug!("{} is {} bytes with {} frames, dropping every other frame", filename, encoded.len(), frames.len());
+      frames = frames.into_iter().step_by(2).collect();
+      delay_ms *= 2;
+      encoded = self.encode(&frames, width, height, delay_ms)?;
+    }
+    File::create(filename)?.write_all(&encoded)?;
+    Ok(())
+  }
+
+  fn encode(&self, frames: &[&Vec<u8>], width: u32, height: u32, delay_ms: u32) -> Result<Vec<u8>> {
+    match self.format {
+      AnimationFormat::Gif => {
+        let mut encoder = gif::Encoder::new(vec![], width as u16, height as u16, &[])?;
+        encoder.set_repeat(gif::Repeat::Infinite)?;
+        for pixels in frames {
+          let mut frame = gif::Frame::from_rgb_speed(width as u16, height as u16, pixels, 10);
+          frame.delay = (delay_ms / 10) as u16;
+          encoder.write_frame(&frame)?;
+        }
+        Ok(encoder.into_inner()?)
+      }
+      AnimationFormat::Webp => {
+        let mut config = WebPConfig::new().map_err(|_| "Invalid WebP configuration")?;
+        config.quality = self.quality.clamp(1, 100) as f32;
+        let mut encoder = AnimEncoder::new(width, height, &config);
+        encoder.set_loop_count(0);
+        for (index, pixels) in frames.iter().enumerate() {
+          encoder.add_frame(AnimFrame::from_rgb(pixels, width, height, (index as u32 * delay_ms) as i32));
+        }
+        let encoded = encoder.try_encode().map_err(|e| format!("WebP encoding failed: {:?}", e))?;
+        Ok(encoded.to_vec())
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use super::*;
+
+  #[test]
+  fn it_saves_each_format() {
+    let (width, height) = (32, 16);
+    let pixels = (0..width * height)
+      .flat_map(|i| [(i % width * 8) as u8, (i / width * 16) as u8, 128])
+      .collect::<Vec<_>>();
+    for (format, magic) in [
+      (ImageFormat::Jpeg, &b"\xFF\xD8"[..]),
+      (ImageFormat::Png, &b"\x89PNG"[..]),
+      (ImageFormat::Webp, &b"RIFF"[..]),
+      (ImageFormat::Avif, &b"\0\0\0"[..]),
+    ] {
+      let encoder = ImageEncoder { format, quality: 80 };
+      encoder.save_rgb("target/gradient", &pixels, width, height).unwrap();
+      let contents = fs::read(format!("target/gradient.{}", format.extension())).unwrap();
+      assert!(contents.starts_with(magic), "{:?}", format);
+    }
+  }
+
+  #[test]
+  fn it_saves_each_animation_format() {
+    let (width, height) = (32, 16);
+    let frames = (0..4u32)
+      .map(|frame| (0..width * height)
+        .flat_map(|i| [((i % width + frame * 8) * 8) as u8, (i / width * 16) as u8, 128])
+        .collect::<Vec<_>>())
+      .collect::<Vec<_>>();
+    for (format, magic) in [(AnimationFormat::Gif, &b"GIF89a"[..]), (AnimationFormat::Webp, &b"RIFF"[..])] {
+      let encoder = AnimationEncoder { format, quality: 60, fps: 8, max_bytes: 0 };
+      encoder.save_rgb("target/animated", &frames, width, height).unwrap();
+      let contents = fs::read(format!("target/animated.{}", format.extension())).unwrap();
+      assert!(contents.starts_with(magic), "{:?}", format);
+    }
+  }
+
+  #[test]
+  fn it_drops_frames_to_fit_the_size() {
+    let (width, height) = (32, 16);
+    let mut seed = 1u32;
+    let frames = (0..8)
+      .map(|_| (0..width * height * 3)
+        .map(|_| {
+          seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+          (seed >> 24) as u8
+        })
+        .collect::<Vec<_>>())
+      .collect::<Vec<_>>();
+    let encoder = AnimationEncoder { format: AnimationFormat::Gif, quality: 60, fps: 8, max_bytes: 0 };
+    encoder.save_rgb("target/noise", &frames, width, height).unwrap();
+    let full = fs::metadata("target/noise.gif").unwrap().len() as usize;
+    let encoder = AnimationEncoder { max_bytes: full * 2 / 3, ..encoder };
+    encoder.save_rgb("target/noise", &frames, width, height).unwrap();
+    let capped = fs::metadata("target/noise.gif").unwrap().len() as usize;
+    assert!(capped <= full * 2 / 3, "{} of {}", capped, full);
+    let encoder = AnimationEncoder { max_bytes: 1, ..encoder };
+    encoder.save_rgb("target/noise", &frames[..1], width, height).unwrap();
+    assert!(fs::metadata("target/noise.gif").unwrap().len() > 1);
+  }
+}
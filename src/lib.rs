@@ -1,19 +1,34 @@
 use std::{error, fmt, fs};
-use std::cmp::Ordering;
 use std::fs::File;
 use std::io::Write;
+use std::ops::{Add, Sub};
+use clap::ValueEnum;
+use ffmpeg_next::{Rational, Rescale};
 use log::info;
 
-use crate::assa::parse_assa_to_dialogue;
+use crate::assa::{bilingual_to_assa, dialogue_to_assa, parse_assa_to_dialogue};
+use crate::audio::AudioFormat;
+use crate::clip::ClipFormat;
+use crate::image::{AnimationFormat, ImageFormat};
 use crate::mp3::AudioSuffix;
-use crate::subrip::parse_subrip_to_dialogue;
-use crate::webvtt::parse_webvtt_to_dialogue;
+use crate::subrip::{dialogue_to_subrip, parse_subrip_to_dialogue};
+use crate::webvtt::{dialogue_to_webvtt, parse_webvtt_to_dialogue};
 
 mod assa;
 pub mod mp3;
 mod subrip;
 mod webvtt;
 pub mod ffmpeg;
+pub mod embedded;
+pub mod image;
+pub mod clip;
+pub mod audio;
+pub mod condense;
+pub mod tags;
+pub mod vocals;
+pub mod plot;
+mod loudness;
+mod spectrum;
 
 pub type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
 
@@ -22,6 +37,8 @@ pub struct Dialogue {
   pub start: Time,
   pub end: Time,
   pub text: String,
+  /// Subtitle image for bitmap subtitles, which have no text
+  pub image: Option<String>,
 }
 
 impl fmt::Display for Dialogue {
@@ -38,61 +55,96 @@ impl Dialogue {
   }
 
   fn shift(&mut self, offset: f32) {
-    let millis = (offset * 1000.0) as u64;
-    self.start = Time::from_millis(self.start.milliseconds() + millis);
-    self.end = Time::from_millis(self.end.milliseconds() + millis);
+    let offset = Time::from_micros((offset as f64 * 1_000_000.0).round() as i64);
+    self.start = self.start + offset;
+    self.end = self.end + offset;
   }
 }
 
-#[derive(Debug, PartialEq)]
+/// Time base of `Time`, for rescaling stream timestamps.
+pub(crate) const MICROSECONDS: Rational = Rational(1, 1_000_000);
+
+/// Media time in microseconds, relative to the start of the video. Negative after shifting
+/// dialogue back past the start.
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
 pub struct Time {
-  hour: u8,
-  min: u8,
-  sec: u8,
-  mil: u16,
+  micros: i64,
 }
 
 impl fmt::Display for Time {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "{}{:02}{:02}", self.hour, self.min, self.sec)
+    write!(f, "{}", self.hms())
   }
 }
 
-impl PartialOrd for Time {
-  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-    Some(self.milliseconds().cmp(&other.milliseconds()))
+impl Add for Time {
+  type Output = Time;
+
+  fn add(self, other: Time) -> Time {
+    Time::from_micros(self.micros.saturating_add(other.micros))
+  }
+}
+
+impl Sub for Time {
+  type Output = Time;
+
+  fn sub(self, other: Time) -> Time {
+    Time::from_micros(self.micros.saturating_sub(other.micros))
   }
 }
 
 impl Time {
-  pub fn from_nanos(nanos: u64) -> Time {
-    Time {
-      hour: (nanos / 3_600_000 % 60) as u8,
-      min: (nanos / 60_000 % 60) as u8,
-      sec: (nanos / 1000 % 60) as u8,
-      mil: (nanos % 1000) as u16,
-    }
+  pub const MAX: Time = Time { micros: i64::MAX };
+
+  pub fn new(hour: u32, min: u32, sec: u32, mil: u32) -> Time {
+    let seconds = (hour as i64 * 60 + min as i64) * 60 + sec as i64;
+    Time::from_micros(seconds * 1_000_000 + mil as i64 * 1000)
+  }
+
+  pub const fn from_micros(micros: i64) -> Time {
+    Time { micros }
+  }
+
+  pub fn from_millis(millis: i64) -> Time {
+    Time::from_micros(millis.saturating_mul(1000))
+  }
+
+  /// Converts a stream timestamp, counted in `time_base` units.
+  pub fn from_timestamp(timestamp: i64, time_base: Rational) -> Time {
+    Time::from_micros(timestamp.rescale(time_base, MICROSECONDS))
+  }
+
+  /// Converts to a stream timestamp, counted in `time_base` units.
+  pub fn timestamp(&self, time_base: Rational) -> i64 {
+    self.micros.rescale(MICROSECONDS, time_base)
   }
 
-  pub fn from_millis(millis: u64) -> Time {
-    Self::from_nanos(millis)
+  pub fn micros(&self) -> i64 {
+    self.micros
   }
 
-  pub fn milliseconds(&self) -> u64 {
-    ((self.hour as u64 * 60 + self.min as u64) * 60 + self.sec as u64) * 1000 + self.mil as u64
+  pub fn milliseconds(&self) -> i64 {
+    self.micros.div_euclid(1000)
   }
 
   pub fn half_way(&self, later: &Time) -> Time {
-    let half = self.milliseconds() + (later.milliseconds() - self.milliseconds()) / 2;
-    Time::from_nanos(half)
+    Time::from_micros(self.micros + (later.micros - self.micros) / 2)
+  }
+
+  /// Hours, minutes, seconds and milliseconds, with negative times clamped to zero.
+  fn parts(&self) -> (i64, i64, i64, i64) {
+    let millis = self.milliseconds().max(0);
+    (millis / 3_600_000, millis / 60_000 % 60, millis / 1000 % 60, millis % 1000)
   }
 
   pub fn hms(&self) -> String {
-    format!("{}{:02}{:02}", self.hour, self.min, self.sec)
+    let (hour, min, sec, _) = self.parts();
+    format!("{}{:02}{:02}", hour, min, sec)
   }
 
   fn colon(&self) -> String {
-    format!("{}:{:02}:{:02}.{:03}", self.hour, self.min, self.sec, self.mil)
+    let (hour, min, sec, mil) = self.parts();
+    format!("{}:{:02}:{:02}.{:03}", hour, min, sec, mil)
   }
 }
 
@@ -124,6 +176,90 @@ fn parse_subtitle_file(path: &str) -> Option<Vec<Dialogue>> {
   None
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum SubtitleFormat {
+  Srt,
+  Vtt,
+  Ass,
+}
+
+impl SubtitleFormat {
+  pub fn extension(&self) -> &'static str {
+    match self {
+      SubtitleFormat::Srt => "srt",
+      SubtitleFormat::Vtt => "vtt",
+      SubtitleFormat::Ass => "ass",
+    }
+  }
+}
+
+pub fn write_subtitle_file(path: &str, dialogue: &[Dialogue], format: SubtitleFormat) -> Result<()> {
+  info!("Writing to {}", path);
+  fs::write(path, subtitle_contents(dialogue, format))?;
+  Ok(())
+}
+
+fn subtitle_contents(dialogue: &[Dialogue], format: SubtitleFormat) -> String {
+  match format {
+    SubtitleFormat::Srt => dialogue_to_subrip(dialogue),
+    SubtitleFormat::Vtt => dialogue_to_webvtt(dialogue),
+    SubtitleFormat::Ass => dialogue_to_assa(dialogue),
+  }
+}
+
+/// Pairs each source line with its overlapping target lines, like the Anki export, and writes
+/// them as one subtitle file for watching with both languages.
+pub fn write_bilingual_file(path: &str, primary: &[Dialogue], secondary: &[Dialogue], format: SubtitleFormat)
+                            -> Result<()> {
+  let pairs: Vec<(&Dialogue, String)> = primary.iter()
+    .map(|first| {
+      let second = find_secondary_matches(first, secondary).iter()
+        .map(|d| plain_text(&d.text))
+        .collect::<Vec<_>>()
+        .join("\n");
+      (first, second)
+    })
+    .collect();
+  let contents = match format {
+    SubtitleFormat::Ass => bilingual_to_assa(&pairs),
+    _ => {
+      let merged = pairs.iter()
+        .map(|(first, second)| Dialogue {
+          start: first.start,
+          end: first.end,
+          text: format!("{}\n{}", plain_text(&first.text), second).trim_end().to_string(),
+          image: None,
+        })
+        .collect::<Vec<_>>();
+      subtitle_contents(&merged, format)
+    }
+  };
+  info!("Writing to {}", path);
+  fs::write(path, contents)?;
+  Ok(())
+}
+
+/// Text with line breaks as newlines and without ASS override tags like `{\i1}`.
+pub(crate) fn plain_text(text: &str) -> String {
+  let mut plain = String::with_capacity(text.len());
+  let mut rest = text;
+  while let Some(open) = rest.find("{\\") {
+    plain.push_str(&rest[..open]);
+    match rest[open..].find('}') {
+      Some(close) => rest = &rest[open + close + 1..],
+      None => {
+        rest = &rest[open..];
+        break;
+      }
+    }
+  }
+  plain.push_str(rest);
+  plain
+    .replace("\\N", "\n")
+    .replace("\\n", "\n")
+    .replace("<br/>", "\n")
+}
+
 pub fn find_secondary_matches<'a>(dialogue: &'a Dialogue, secondary: &'a [Dialogue]) ->
 Vec<&'a Dialogue> {
   secondary
@@ -131,7 +267,23 @@ Vec<&'a Dialogue> {
     .collect()
 }
 
-pub fn generate_tab_separated(primary: Vec<Dialogue>, secondary: Vec<Dialogue>, output: &str, prefix: &str, suffix: AudioSuffix) {
+/// Formats of the media files that cards reference, which are named after the dialogue.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CardMedia {
+  pub audio: AudioFormat,
+  /// Slowed down audio clips in their own field
+  pub slow: bool,
+  /// Waveform or spectrogram of each clip in the last field
+  pub plot: bool,
+  pub image: ImageFormat,
+  pub animation: Option<AnimationFormat>,
+  pub clip: Option<ClipFormat>,
+  /// Numbered screenshots of each dialogue, all in the image field, when more than one
+  pub screenshots: usize,
+}
+
+pub fn generate_tab_separated(primary: Vec<Dialogue>, secondary: Vec<Dialogue>, output: &str, prefix: &str, suffix: AudioSuffix,
+                              media: &CardMedia) {
   let filename = format!("{}/{}-anki.tsv", output, prefix);
   info!("Writing to {}", filename);
 
@@ -140,39 +292,57 @@ pub fn generate_tab_separated(primary: Vec<Dialogue>, secondary: Vec<Dialogue>,
     let half = first.start.half_way(&first.end);
 
     let second = find_secondary_matches(first, &secondary);
-    let text = first.text
-      .replace("\n", " ")
-      .replace("\\N", " ")
-      .replace("\\n", " ");
-    let second: String = second.iter().map(|d| d.text.clone()).collect::<Vec<_>>().join(" ")
-      .replace("\n", " ")
-      .replace("\\N", " ")
-      .replace("\\n", " ");
-
-    let (id, sound, image) = match suffix {
-      AudioSuffix::None => {
-        let id = format!("{}_{}", prefix, first.start);
-        let sound = format!("[sound:{}_{}.mp3]", prefix, first.start);
-        let image = format!("<img src=\"{}_{}.jpg\">", prefix, first.start.hms());
-        (id, sound, image)
-      }
-      AudioSuffix::EndTime => {
-        let id = format!("{}_{}", prefix, half);
-        let sound = format!("[sound:{}_{}-{}.mp3]", prefix, first.start, first.end);
-        let image = format!("<img src=\"{}_{}.jpg\">", prefix, half.hms());
-        (id, sound, image)
-      }
+    let text = single_line(&first.text);
+    let second = single_line(&second.iter().map(|d| d.text.clone()).collect::<Vec<_>>().join(" "));
+
+    let sound = format!("[sound:{}]", suffix.name(prefix, first, media.audio.extension()));
+    let slow = if media.slow {
+      format!("[sound:{}]", suffix.slow_name(prefix, first, media.audio.extension()))
+    } else {
+      String::new()
+    };
+    let plot = if media.plot {
+      format!("<img src=\"{}.png\">", suffix.plot_name(prefix, first))
+    } else {
+      String::new()
+    };
+    let (id, stem) = match suffix {
+      AudioSuffix::None => (format!("{}_{}", prefix, first.start), format!("{}_{}", prefix, first.start.hms())),
+      AudioSuffix::EndTime => (format!("{}_{}", prefix, half), format!("{}_{}", prefix, half.hms())),
+    };
+    let image = match media.screenshots {
+      0 | 1 => format!("<img src=\"{}.{}\">", stem, media.image.extension()),
+      count => (1..=count)
+        .map(|number| format!("<img src=\"{}_{}.{}\">", stem, number, media.image.extension()))
+        .collect(),
     };
-    writeln!(&writer, "{}\t{}\t{}\t{}\t{}\t{}", id, sound, image, text, second, prefix).unwrap();
+    let animation = media.animation
+      .map(|animation| format!("<img src=\"{}_animated.{}\">", stem, animation.extension()))
+      .unwrap_or_default();
+    let clip = media.clip
+      .map(|clip| format!("[sound:{}.{}]", stem, clip.extension()))
+      .unwrap_or_default();
+    let subtitle = first.image.as_ref()
+      .map(|image| format!("<img src=\"{}\">", image))
+      .unwrap_or_default();
+    writeln!(&writer, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", id, sound, image, text, second, prefix,
+             subtitle, animation, clip, slow, plot).unwrap();
   }
 }
 
-pub fn sample_range(sample: &Option<u32>) -> (u64, u64) {
-  let (start, end) = match sample {
-    None => (0, u64::MAX),
-    Some(start) => (*start as u64 * 1000 * 60, (*start as u64 + 3) * 1000 * 60)
-  };
-  (start, end)
+/// Text with its line breaks, and the escaped ones of Substation Alpha, replaced by spaces.
+pub(crate) fn single_line(text: &str) -> String {
+  text
+    .replace("\n", " ")
+    .replace("\\N", " ")
+    .replace("\\n", " ")
+}
+
+pub fn sample_range(sample: &Option<u32>) -> (Time, Time) {
+  match sample {
+    None => (Time::default(), Time::MAX),
+    Some(start) => (Time::new(0, *start, 0, 0), Time::new(0, *start + 3, 0, 0))
+  }
 }
 
 #[cfg(test)]
@@ -181,39 +351,83 @@ mod tests {
 
   #[test]
   fn it_halves_properly() {
-    let start = Time { hour: 0, min: 1, sec: 59, mil: 10 };
-    let end = Time { hour: 0, min: 2, sec: 2, mil: 50 };
+    let start = Time::new(0, 1, 59, 10);
+    let end = Time::new(0, 2, 2, 50);
     let half = start.half_way(&end);
-    assert_eq!(Time { hour: 0, min: 2, sec: 0, mil: 530 }, half);
+    assert_eq!(Time::new(0, 2, 0, 530), half);
+  }
+
+  #[test]
+  fn it_converts_stream_timestamps() {
+    let time = Time::from_timestamp(90_062_003, Rational(1, 1000));
+    assert_eq!(Time::new(25, 1, 2, 3), time);
+    let time = Time::from_timestamp(1_801_801, Rational(1, 90_000));
+    assert_eq!(20_020_011, time.micros());
+    assert_eq!(1_801_801, time.timestamp(Rational(1, 90_000)));
   }
 
   #[test]
   fn it_halves_first_duration() {
     // ichigo-1_1_0.01.39.620-0.01.41.620.mp3
     // ichigo-1_1_0.01.40.620.jpg
-    let start = Time { hour: 0, min: 1, sec: 39, mil: 620 };
-    let end = Time { hour: 0, min: 1, sec: 41, mil: 620 };
+    let start = Time::new(0, 1, 39, 620);
+    let end = Time::new(0, 1, 41, 620);
     let diff = end.milliseconds() - start.milliseconds();
     assert_eq!(2000, diff);
     let result = start.half_way(&end);
-    assert_eq!(Time { hour: 0, min: 1, sec: 40, mil: 620 }, result)
+    assert_eq!(Time::new(0, 1, 40, 620), result)
   }
 
   #[test]
   fn it_halves_last_duration() {
     // ichigo-1_1_0.24.03.080-0.24.04.250.mp3
     // ichigo-1_1_0.24.03.665.jpg
-    let start = Time { hour: 0, min: 24, sec: 3, mil: 80 };
-    let end = Time { hour: 0, min: 24, sec: 4, mil: 250 };
+    let start = Time::new(0, 24, 3, 80);
+    let end = Time::new(0, 24, 4, 250);
     let result = start.half_way(&end);
-    assert_eq!(Time { hour: 0, min: 24, sec: 3, mil: 665 }, result);
-    assert_eq!("0.24.03.665", format!("{}", result))
+    assert_eq!(Time::new(0, 24, 3, 665), result);
+    assert_eq!("02403", format!("{}", result));
+    assert_eq!("0:24:03.665", result.colon())
+  }
+
+  #[test]
+  fn it_converts_millis() {
+    let time = Time::from_millis(1451951);
+    assert_eq!("02411", format!("{}", time));
+    assert_eq!("0:24:11.951", time.colon())
+  }
+
+  #[test]
+  fn it_keeps_hours_past_a_day() {
+    let time = Time::new(25, 1, 2, 3) + Time::from_micros(500);
+    assert_eq!("250102", time.hms());
+    assert_eq!(90_062_003_500, time.micros());
+    assert_eq!(90_062_003, time.milliseconds());
+  }
+
+  #[test]
+  fn it_shifts_back_before_start() {
+    let mut dialogue = Dialogue { start: Time::new(0, 0, 1, 0), end: Time::new(0, 0, 2, 0), text: String::new(), image: None };
+    dialogue.shift(-1.5);
+    assert_eq!(-500, dialogue.start.milliseconds());
+    assert_eq!(500, dialogue.end.milliseconds());
+    assert_eq!("0:00:00.000", dialogue.start.colon());
   }
 
   #[test]
-  fn it_converts_nanos() {
-    let time = Time::from_nanos(1451951);
-    assert_eq!("0.24.11.951", format!("{}", time))
+  fn it_strips_override_tags() {
+    assert_eq!("えー  特にこれといって\n特徴ないのが特徴です",
+               plain_text("{\\i1}えー  特にこれといって{\\i0}\\N特徴ないのが特徴です"));
+    assert_eq!("Line one\nline two", plain_text("Line one<br/>line two"));
+  }
+
+  #[test]
+  fn it_writes_bilingual_webvtt() {
+    let primary = offset_subtitle_file("tests/totoro.ja.vtt", &None).unwrap();
+    let secondary = offset_subtitle_file("tests/totoro.en.vtt", &None).unwrap();
+    write_bilingual_file("target/totoro-bilingual.vtt", &primary, &secondary, SubtitleFormat::Vtt).unwrap();
+    let contents = fs::read_to_string("target/totoro-bilingual.vtt").unwrap();
+    assert!(contents.contains("<c.japanese>はやく！</c.japanese>\nCome on!\n"));
   }
 
   #[test]
@@ -221,6 +435,23 @@ mod tests {
     let primary = offset_subtitle_file("tests/totoro.ja.srt", &None).unwrap();
     let secondary = offset_subtitle_file("tests/totoro.en.ass", &None).unwrap();
     assert_eq!(551, secondary.len());
-    generate_tab_separated(primary, secondary, "target", "totoro", AudioSuffix::EndTime);
+    let media = CardMedia {
+      audio: AudioFormat::Opus,
+      slow: true,
+      plot: true,
+      image: ImageFormat::Webp,
+      animation: Some(AnimationFormat::Gif),
+      clip: Some(ClipFormat::Mp4),
+      screenshots: 3,
+    };
+    generate_tab_separated(primary, secondary, "target", "totoro", AudioSuffix::EndTime, &media);
+    let contents = fs::read_to_string("target/totoro-anki.tsv").unwrap();
+    assert!(contents.contains("_1.webp\"><img src=\"totoro_"));
+    assert!(contents.contains("_3.webp\">\t"));
+    assert!(contents.contains("_animated.gif\">\t[sound:totoro_"));
+    assert!(contents.contains(".mp4]\t[sound:totoro_"));
+    assert!(contents.contains("_slow.opus]\t<img src=\"totoro_"));
+    assert!(contents.contains("_plot.png\">\n"));
+    assert!(contents.contains(".opus]\t<img src=\"totoro_"));
   }
 }
\ No newline at end of file
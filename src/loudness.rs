@@ -0,0 +1,152 @@
+/// Sample rate that the K-weighting coefficients are for.
+pub(crate) const METER_RATE: u32 = 48000;
+
+/// Loudness is measured on blocks of 400 ms that overlap by 75%, made of 100 ms steps.
+const STEP_SAMPLES: usize = METER_RATE as usize / 10;
+const BLOCK_STEPS: usize = 4;
+
+/// Blocks quieter than this are silence.
+const ABSOLUTE_GATE: f64 = -70.0;
+
+/// Blocks this far below the loudness of the ungated blocks are pauses.
+const RELATIVE_GATE: f64 = -10.0;
+
+/// Second order IIR filter in direct form I.
+#[derive(Clone, Copy, Debug, Default)]
+struct Biquad {
+  b: [f64; 3],
+  a: [f64; 2],
+  x: [f64; 2],
+  y: [f64; 2],
+}
+
+impl Biquad {
+  fn new(b: [f64; 3], a: [f64; 2]) -> Biquad {
+    Biquad { b, a, ..Biquad::default() }
+  }
+
+  fn process(&mut self, x: f64) -> f64 {
+    let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+      - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+    self.x = [x, self.x[0]];
+    self.y = [y, self.y[0]];
+    y
+  }
+}
+
+/// Integrated loudness of stereo audio at 48 kHz as in ITU-R BS.1770, in LUFS.
+#[derive(Clone, Debug)]
+pub(crate) struct LoudnessMeter {
+  /// High shelf and high pass of the K-weighting, for each channel
+  filters: [[Biquad; 2]; 2],
+  /// Sum of the squared weighted samples of both channels in each complete step
+  steps: Vec<f64>,
+  step: f64,
+  step_samples: usize,
+}
+
+impl Default for LoudnessMeter {
+  fn default() -> Self {
+    let shelf = Biquad::new([1.53512485958697, -2.69169618940638, 1.19839281085285],
+                            [-1.69065929318241, 0.73248077421585]);
+    let high_pass = Biquad::new([1.0, -2.0, 1.0], [-1.99004745483398, 0.99007225036621]);
+    LoudnessMeter { filters: [[shelf, high_pass]; 2], steps: vec![], step: 0.0, step_samples: 0 }
+  }
+}
+
+impl LoudnessMeter {
+  /// Adds left and right samples.
+  pub fn add(&mut self, samples: &[(f32, f32)]) {
+    for (left, right) in samples {
+      for (filters, sample) in self.filters.iter_mut().zip([left, right]) {
+        let shelved = filters[0].process(*sample as f64);
+        let weighted = filters[1].process(shelved);
+        self.step += weighted * weighted;
+      }
+      self.step_samples += 1;
+      if self.step_samples == STEP_SAMPLES {
+        self.steps.push(self.step);
+        self.step = 0.0;
+        self.step_samples = 0;
+      }
+    }
+  }
+
+  /// Gated loudness, or None for silence. Audio shorter than a block is measured as one block.
+  pub fn integrated(&self) -> Option<f64> {
+    let blocks = if self.steps.len() < BLOCK_STEPS {
+      let samples = self.steps.len() * STEP_SAMPLES + self.step_samples;
+      if samples == 0 {
+        return None;
+      }
+      vec![(self.steps.iter().sum::<f64>() + self.step) / samples as f64]
+    } else {
+      self.steps.windows(BLOCK_STEPS)
+        .map(|block| block.iter().sum::<f64>() / (BLOCK_STEPS * STEP_SAMPLES) as f64)
+        .collect()
+    };
+    let audible = blocks.into_iter()
+      .filter(|power| loudness(*power) > ABSOLUTE_GATE)
+      .collect::<Vec<_>>();
+    if audible.is_empty() {
+      return None;
+    }
+    let relative_gate = loudness(mean(&audible)) + RELATIVE_GATE;
+    let gated = audible.into_iter()
+      .filter(|power| loudness(*power) > relative_gate)
+      .collect::<Vec<_>>();
+    Some(loudness(mean(&gated)))
+  }
+}
+
+fn loudness(power: f64) -> f64 {
+  -0.691 + 10.0 * power.log10()
+}
+
+fn mean(values: &[f64]) -> f64 {
+  values.iter().sum::<f64>() / values.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+  use std::f64::consts::PI;
+
+  use super::*;
+
+  fn sine(frequency: f64, amplitude: f64, seconds: f64) -> Vec<(f32, f32)> {
+    (0..(seconds * METER_RATE as f64) as usize)
+      .map(|i| (amplitude * (2.0 * PI * frequency * i as f64 / METER_RATE as f64).sin()) as f32)
+      .map(|sample| (sample, sample))
+      .collect()
+  }
+
+  #[test]
+  fn it_measures_reference_tone() {
+    // A 1 kHz sine at -20 dBFS in both channels is -20 LUFS, within the filter response at 1 kHz.
+    let mut meter = LoudnessMeter::default();
+    meter.add(&sine(1000.0, 0.1, 3.0));
+    let measured = meter.integrated().unwrap();
+    assert!((measured + 20.0).abs() < 0.1, "{}", measured);
+  }
+
+  #[test]
+  fn it_gates_silence_and_pauses() {
+    let mut meter = LoudnessMeter::default();
+    assert_eq!(None, meter.integrated());
+    meter.add(&vec![(0.0, 0.0); METER_RATE as usize * 2]);
+    assert_eq!(None, meter.integrated());
+    meter.add(&sine(1000.0, 0.1, 2.0));
+    meter.add(&sine(1000.0, 0.001, 2.0));
+    // Only the blocks across the change are between the two tones, instead of -23 LUFS on average.
+    let measured = meter.integrated().unwrap();
+    assert!((measured + 20.0).abs() < 1.0, "{}", measured);
+  }
+
+  #[test]
+  fn it_measures_short_clips_as_one_block() {
+    let mut meter = LoudnessMeter::default();
+    meter.add(&sine(1000.0, 0.1, 0.2));
+    let measured = meter.integrated().unwrap();
+    assert!((measured + 20.0).abs() < 0.5, "{}", measured);
+  }
+}
@@ -4,12 +4,21 @@ use clap::{Parser, Subcommand};
 use clap::arg;
 use env_logger::Env;
 use env_logger::Target::Stdout;
-use log::debug;
+use log::{debug, info};
 
 use ::rust2srs::Result;
-use rust2srs::ffmpeg::extract_screenshots;
-use rust2srs::mp3::{AudioSuffix, extract_sound_clips};
-use rust2srs::{generate_tab_separated, offset_subtitle_file};
+use rust2srs::audio::{AudioEncoder, AudioFormat, AudioOptions, extract_audio_clips, LoudnessScope, Normalization};
+use rust2srs::clip::{ClipFormat, ClipOptions, extract_video_clips};
+use rust2srs::condense::{condense_audio, CondenseOptions};
+use rust2srs::embedded::{list_subtitle_streams, offset_subtitle_stream};
+use rust2srs::ffmpeg::{AnimationOptions, Crop, extract_screenshots, FrameSelection, ScalerAlgorithm, ScreenshotOptions};
+use rust2srs::image::{AnimationEncoder, AnimationFormat, ImageEncoder, ImageFormat};
+use rust2srs::mp3::{AudioPadding, AudioSuffix, extract_sound_clips};
+use rust2srs::plot::{PlotKind, PlotOptions};
+use rust2srs::tags::TagSource;
+use rust2srs::vocals::VocalIsolation;
+use rust2srs::{CardMedia, Dialogue, generate_tab_separated, offset_subtitle_file, SubtitleFormat, Time, write_bilingual_file,
+               write_subtitle_file};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -18,6 +27,10 @@ struct Cli {
   #[arg(short, long)]
   source: String,
 
+  /// Subtitle stream index or language code when the source is a video
+  #[arg(long)]
+  subtitle_stream: Option<String>,
+
   /// Offset
   #[arg(long)]
   offset: Option<f32>,
@@ -34,6 +47,38 @@ struct Cli {
   #[arg(long)]
   sample: Option<u32>,
 
+  /// Audio clip format
+  #[arg(long, value_enum, default_value = "mp3")]
+  audio_format: AudioFormat,
+
+  /// Screenshot format
+  #[arg(long, value_enum, default_value = "jpeg")]
+  image_format: ImageFormat,
+
+  /// Animated clip format, for an animation of each dialogue next to the screenshot
+  #[arg(long, value_enum)]
+  animation: Option<AnimationFormat>,
+
+  /// Video clip format, for a clip of each dialogue with its audio
+  #[arg(long, value_enum)]
+  clip: Option<ClipFormat>,
+
+  /// Speed of a slowed down audio clip of each dialogue, added next to the normal one
+  #[arg(long, value_parser = parse_speed)]
+  slow: Option<f64>,
+
+  /// Image of each audio clip, added as the last card field
+  #[arg(long, value_enum)]
+  plot: Option<PlotKind>,
+
+  /// Screenshots of each dialogue, from equal parts of it
+  #[arg(long, default_value = "1")]
+  screenshots: usize,
+
+  /// Tile the screenshots of each dialogue into one image
+  #[arg(long, default_value = "false")]
+  contact_sheet: bool,
+
   /// Verbose logging
   #[arg(short, long, default_value = "false")]
   verbose: bool,
@@ -48,11 +93,219 @@ enum Commands {
     /// Media file
     #[arg(short, long)]
     video: String,
+
+    /// Number of parallel decoders
+    #[arg(short, long, default_value = "1")]
+    jobs: usize,
+
+    /// Screenshot width, keeping the aspect ratio unless the height is also set
+    #[arg(long)]
+    image_width: Option<u32>,
+
+    /// Screenshot height, keeping the aspect ratio unless the width is also set
+    #[arg(long)]
+    image_height: Option<u32>,
+
+    /// Crop to width:height:x:y, or auto to remove black bars
+    #[arg(long)]
+    crop: Option<Crop>,
+
+    /// Scaling algorithm
+    #[arg(long, value_enum, default_value = "bilinear")]
+    scaler: ScalerAlgorithm,
+
+    /// Screenshot quality from 1 to 100 for JPEG and AVIF
+    #[arg(long, default_value = "65")]
+    quality: u8,
+
+    /// Frame to take within each dialogue
+    #[arg(long, value_enum, default_value = "midpoint")]
+    frame: FrameSelection,
+
+    /// Deinterlace DVD and broadcast sources
+    #[arg(long, default_value = "false")]
+    deinterlace: bool,
+
+    /// Draw the source subtitles onto the screenshots
+    #[arg(long, default_value = "false")]
+    burn_in: bool,
+
+    /// Also draw these target language subtitles, above the source subtitles
+    #[arg(long)]
+    burn_target: Option<String>,
+
+    /// Offset of the --burn-target subtitles
+    #[arg(long)]
+    burn_target_offset: Option<f32>,
+
+    /// Subtitle stream index or language code when the --burn-target is a video
+    #[arg(long)]
+    burn_target_stream: Option<String>,
+
+    /// Animated clip width
+    #[arg(long, default_value = "320")]
+    animation_width: u32,
+
+    /// Animated clip frames per second
+    #[arg(long, default_value = "8")]
+    animation_fps: u32,
+
+    /// Longest animated clip in seconds
+    #[arg(long, default_value = "4")]
+    animation_length: u32,
+
+    /// Largest animated clip in kilobytes, dropping frames until it fits, or 0 for no limit
+    #[arg(long, default_value = "500")]
+    animation_size: usize,
   },
-  Audio {
+  /// Encode a video clip of each dialogue, in the --clip format
+  Clip {
     /// Media file
     #[arg(short, long)]
+    video: String,
+
+    /// Clip height, keeping the aspect ratio
+    #[arg(long, default_value = "360")]
+    clip_height: u32,
+
+    /// Video bitrate in kbit/s
+    #[arg(long, default_value = "600")]
+    video_bitrate: usize,
+
+    /// Audio bitrate in kbit/s
+    #[arg(long, default_value = "96")]
+    audio_bitrate: usize,
+
+    /// Milliseconds included before each dialogue
+    #[arg(long, default_value = "250")]
+    pad_start: i64,
+
+    /// Milliseconds included after each dialogue
+    #[arg(long, default_value = "250")]
+    pad_end: i64,
+  },
+  Audio {
+    /// MP3 to cut, or video or audio file to decode and encode
+    #[arg(short, long)]
+    audio: String,
+
+    /// Audio stream index or language code when the audio is decoded
+    #[arg(long)]
+    audio_stream: Option<String>,
+
+    /// Bitrate in kbit/s when the audio is decoded, except for WAV
+    #[arg(long, default_value = "128")]
+    bitrate: usize,
+
+    /// Downmix decoded audio to one channel
+    #[arg(long, default_value = "false")]
+    mono: bool,
+
+    /// Decode an MP3 too, to cut at the exact sample instead of copying whole frames
+    #[arg(long, default_value = "false")]
+    exact: bool,
+
+    /// Milliseconds included before each dialogue
+    #[arg(long, default_value = "0")]
+    pad_start: i64,
+
+    /// Milliseconds included after each dialogue
+    #[arg(long, default_value = "0")]
+    pad_end: i64,
+
+    /// Keep the padding from reaching into the previous or next dialogue
+    #[arg(long, default_value = "false")]
+    clamp_padding: bool,
+
+    /// Milliseconds faded in and out at the edges of each decoded clip
+    #[arg(long, default_value = "10")]
+    fade: i64,
+
+    /// Normalize the loudness of each clip, or of all clips together
+    #[arg(long, value_enum)]
+    normalize: Option<LoudnessScope>,
+
+    /// Target loudness in LUFS
+    #[arg(long, default_value = "-16", allow_negative_numbers = true)]
+    loudness: f64,
+
+    /// Highest true peak in dBFS after normalizing
+    #[arg(long, default_value = "-1.5", allow_negative_numbers = true)]
+    peak: f64,
+
+    /// Target language subtitles, for the comment tag of each clip
+    #[arg(short, long)]
+    target: Option<String>,
+
+    /// Subtitle stream index or language code when the target is a video
+    #[arg(long)]
+    target_stream: Option<String>,
+
+    /// Offset of the target subtitles, as for `anki`
+    #[arg(long)]
+    target_offset: Option<f32>,
+
+    /// Embed the screenshot of each dialogue in the --image-format as cover art
+    #[arg(long, default_value = "false")]
+    cover: bool,
+
+    /// Keep only what is mixed in both channels, where dialogue usually is, as mono
+    #[arg(long, default_value = "false")]
+    center: bool,
+
+    /// Subtract the background of the gaps between dialogue, scaled by this factor, e.g. 1.0
+    #[arg(long)]
+    denoise: Option<f32>,
+
+    /// Width of the --plot in pixels
+    #[arg(long, default_value = "600")]
+    plot_width: u32,
+
+    /// Height of the --plot in pixels
+    #[arg(long, default_value = "160")]
+    plot_height: u32,
+
+    /// Draw the pitch contour over the --plot
+    #[arg(long, default_value = "false")]
+    pitch: bool,
+  },
+  /// Join the dialogue into one audio file for passive listening, in the --audio-format
+  Condense {
+    /// Video or audio file to decode
+    #[arg(short, long)]
     audio: String,
+
+    /// Audio stream index or language code
+    #[arg(long)]
+    audio_stream: Option<String>,
+
+    /// Bitrate in kbit/s, except for WAV
+    #[arg(long, default_value = "128")]
+    bitrate: usize,
+
+    /// Downmix to one channel
+    #[arg(long, default_value = "false")]
+    mono: bool,
+
+    /// Milliseconds included before each dialogue
+    #[arg(long, default_value = "250")]
+    pad_start: i64,
+
+    /// Milliseconds included after each dialogue
+    #[arg(long, default_value = "250")]
+    pad_end: i64,
+
+    /// Milliseconds of silence between dialogue that is kept instead of cut
+    #[arg(long, default_value = "1000")]
+    gap: i64,
+
+    /// Milliseconds faded in and out where the dialogue is joined
+    #[arg(long, default_value = "10")]
+    fade: i64,
+
+    /// Add a chapter for every this many minutes of the episode
+    #[arg(long)]
+    chapter_minutes: Option<u32>,
   },
   Anki {
     /// Source language subtitles
@@ -62,9 +315,53 @@ enum Commands {
     /// Offset
     #[arg(long)]
     offset: Option<f32>,
+
+    /// Subtitle stream index or language code when the target is a video
+    #[arg(long)]
+    target_stream: Option<String>,
+  },
+  /// List the subtitle streams in the source video
+  Streams,
+  /// Write the source subtitles in another format
+  Convert {
+    /// Subtitle format
+    #[arg(short, long, value_enum)]
+    format: SubtitleFormat,
+  },
+  /// Write source and target subtitles together for watching
+  Bilingual {
+    /// Target language subtitles
+    #[arg(short, long)]
+    target: String,
+
+    /// Offset
+    #[arg(long)]
+    offset: Option<f32>,
+
+    /// Subtitle stream index or language code when the target is a video
+    #[arg(long)]
+    target_stream: Option<String>,
+
+    /// Subtitle format
+    #[arg(short, long, value_enum, default_value = "ass")]
+    format: SubtitleFormat,
   },
 }
 
+fn load_subtitles(path: &str, stream: &Option<String>, offset: &Option<f32>, args: &Cli) -> Result<Vec<Dialogue>> {
+  match stream {
+    Some(stream) => offset_subtitle_stream(path, stream, &args.output, &args.prefix, offset),
+    None => Ok(offset_subtitle_file(path, offset).expect("Unrecognized subtitle format")),
+  }
+}
+
+fn parse_speed(value: &str) -> std::result::Result<f64, String> {
+  match value.parse::<f64>() {
+    Ok(speed) if speed > 0.0 && speed < 1.0 => Ok(speed),
+    _ => Err(format!("expected a speed between 0 and 1, not {}", value)),
+  }
+}
+
 fn main() -> Result<()> {
   let args = Cli::parse();
   let level = if args.verbose { "debug" } else { "info" };
@@ -73,18 +370,139 @@ fn main() -> Result<()> {
   ).target(Stdout).init();
   debug!("Verbose logging");
 
-  let source = offset_subtitle_file(&args.source, &args.offset).expect("Unrecognized subtitle format");
-  match args.command {
-    Commands::Video { video } => {
-      extract_screenshots(&video, &args.output, &args.prefix, &source, &args.sample)?;
+  if let Commands::Streams = args.command {
+    for stream in list_subtitle_streams(&args.source)? {
+      info!("{}", stream);
+    }
+    return Ok(());
+  }
+
+  let source = load_subtitles(&args.source, &args.subtitle_stream, &args.offset, &args)?;
+  match &args.command {
+    Commands::Video {
+      video, jobs, image_width, image_height, crop, scaler, quality, frame, deinterlace, burn_in, burn_target,
+      burn_target_offset, burn_target_stream, animation_width, animation_fps, animation_length, animation_size
+    } => {
+      let burn_in = if *burn_in || burn_target.is_some() {
+        let filename = format!("{}/{}-burn-in.ass", args.output, args.prefix);
+        match burn_target {
+          None => write_subtitle_file(&filename, &source, SubtitleFormat::Ass)?,
+          Some(target) => {
+            let target = load_subtitles(target, burn_target_stream, burn_target_offset, &args)?;
+            write_bilingual_file(&filename, &source, &target, SubtitleFormat::Ass)?;
+          }
+        }
+        Some(filename)
+      } else {
+        None
+      };
+      let options = ScreenshotOptions {
+        jobs: *jobs,
+        width: *image_width,
+        height: *image_height,
+        crop: *crop,
+        scaler: *scaler,
+        image: ImageEncoder { format: args.image_format, quality: *quality },
+        frame: *frame,
+        deinterlace: *deinterlace,
+        burn_in,
+        animation: args.animation.map(|format| AnimationOptions {
+          encoder: AnimationEncoder {
+            format,
+            quality: *quality,
+            fps: *animation_fps,
+            max_bytes: *animation_size * 1000,
+          },
+          width: *animation_width,
+          max_length: Time::from_millis(*animation_length as i64 * 1000),
+        }),
+        screenshots: args.screenshots,
+        contact_sheet: args.contact_sheet,
+      };
+      extract_screenshots(video, &args.output, &args.prefix, &source, &args.sample, &options)?;
+    }
+    Commands::Clip { video, clip_height, video_bitrate, audio_bitrate, pad_start, pad_end } => {
+      let options = ClipOptions {
+        format: args.clip.unwrap_or(ClipFormat::Mp4),
+        height: *clip_height,
+        video_bitrate: *video_bitrate,
+        audio_bitrate: *audio_bitrate,
+        pad_start: Time::from_millis(*pad_start),
+        pad_end: Time::from_millis(*pad_end),
+      };
+      extract_video_clips(video, &args.output, &args.prefix, &source, &args.sample, &options)?;
+    }
+    Commands::Audio {
+      audio, audio_stream, bitrate, mono, exact, pad_start, pad_end, clamp_padding, fade, normalize, loudness, peak,
+      target, target_stream, target_offset, cover, center, denoise, plot_width, plot_height, pitch
+    } => {
+      let translation = match target {
+        Some(target) => load_subtitles(target, target_stream, target_offset, &args)?,
+        None => vec![],
+      };
+      let options = AudioOptions {
+        stream: audio_stream.clone(),
+        encoder: AudioEncoder { format: args.audio_format, bitrate: *bitrate, mono: *mono },
+        padding: AudioPadding {
+          start: Time::from_millis(*pad_start),
+          end: Time::from_millis(*pad_end),
+          clamp: *clamp_padding,
+        },
+        fade: Time::from_millis(*fade),
+        normalization: normalize.map(|scope| Normalization { scope, target: *loudness, peak: *peak }),
+        slow: args.slow,
+        tags: TagSource { translation: &translation, cover: cover.then_some(args.image_format) },
+        isolation: (*center || denoise.is_some())
+          .then(|| VocalIsolation { center: *center, denoise: denoise.unwrap_or_default() }),
+        plot: args.plot.map(|kind| PlotOptions { kind, width: *plot_width, height: *plot_height, pitch: *pitch }),
+      };
+      let copy_frames = args.audio_format == AudioFormat::Mp3 && !exact && !mono && *fade <= 0 && normalize.is_none()
+        && args.slow.is_none() && options.isolation.is_none() && options.plot.is_none();
+      if copy_frames && audio.to_lowercase().ends_with(".mp3") {
+        extract_sound_clips(audio, &args.output, &args.prefix, &source, AudioSuffix::None, args.sample,
+                            &options.padding, &options.tags)?;
+      } else {
+        extract_audio_clips(audio, &args.output, &args.prefix, &source, AudioSuffix::None, &args.sample, &options)?;
+      }
+    }
+    Commands::Condense { audio, audio_stream, bitrate, mono, pad_start, pad_end, gap, fade, chapter_minutes } => {
+      let options = CondenseOptions {
+        stream: audio_stream.clone(),
+        encoder: AudioEncoder { format: args.audio_format, bitrate: *bitrate, mono: *mono },
+        padding: AudioPadding {
+          start: Time::from_millis(*pad_start),
+          end: Time::from_millis(*pad_end),
+          clamp: false,
+        },
+        gap: Time::from_millis(*gap),
+        fade: Time::from_millis(*fade),
+        chapter_minutes: *chapter_minutes,
+      };
+      condense_audio(audio, &args.output, &args.prefix, &source, &args.sample, &options)?;
+    }
+    Commands::Anki { target, offset, target_stream } => {
+      let target = load_subtitles(target, target_stream, offset, &args)?;
+      let media = CardMedia {
+        audio: args.audio_format,
+        slow: args.slow.is_some(),
+        plot: args.plot.is_some(),
+        image: args.image_format,
+        animation: args.animation,
+        clip: args.clip,
+        screenshots: if args.contact_sheet { 1 } else { args.screenshots },
+      };
+      generate_tab_separated(source, target, &args.output, &args.prefix, AudioSuffix::None, &media);
     }
-    Commands::Audio { audio } => {
-      extract_sound_clips(&audio, &args.output, &args.prefix, &source, AudioSuffix::None, args.sample)?;
+    Commands::Convert { format } => {
+      let filename = format!("{}/{}.{}", args.output, args.prefix, format.extension());
+      write_subtitle_file(&filename, &source, *format)?;
     }
-    Commands::Anki { target, offset } => {
-      let target = offset_subtitle_file(&target, &offset).expect("Unrecognized subtitle format");
-      generate_tab_separated(source, target, &args.output, &args.prefix, AudioSuffix::None);
+    Commands::Bilingual { target, offset, target_stream, format } => {
+      let target = load_subtitles(target, target_stream, offset, &args)?;
+      let filename = format!("{}/{}-bilingual.{}", args.output, args.prefix, format.extension());
+      write_bilingual_file(&filename, &source, &target, *format)?;
     }
+    Commands::Streams => {}
   }
   Ok(())
 }
\ No newline at end of file
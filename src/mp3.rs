@@ -1,70 +1,211 @@
 use std::fs;
 use std::fs::write;
 use std::path::Path;
-use log::{debug, info};
+use log::{debug, info, warn};
 
 use rmp3::{Decoder, Frame};
 
-use crate::{Dialogue, Result, sample_range};
+use crate::{Dialogue, Result, sample_range, Time};
+use crate::tags::TagSource;
+
+/// MPEG frames before the clip that are copied along, as a frame may start its data in the bit
+/// reservoir of the frames before it.
+const RESERVOIR_FRAMES: usize = 1;
 
 pub struct Mp3 {
   bytes: Vec<u8>,
+  frames: Vec<AudioFrame>,
+}
+
+struct AudioFrame {
+  position: usize,
+  length: usize,
+  /// Milliseconds from the start of the file to the end of the frame
+  end: f64,
+  bitrate: u32,
+  sample_rate: u32,
+  samples: usize,
 }
 
 impl Mp3 {
   fn new<P: AsRef<Path>>(path: P) -> Result<Mp3> {
     let bytes = fs::read(path)?;
-    Ok(Mp3 { bytes })
+    let frames = audio_frames(&bytes);
+    Ok(Mp3 { bytes, frames })
   }
 
-  fn slice<P: AsRef<Path>>(&self, path: P, start: u64, end: u64) -> Result<()> {
-    let mut decoder = Decoder::new(&self.bytes);
-    let mut duration: f64 = 0.0;
-    let mut index = None;
-    while let Some(frame) = decoder.peek() {
-      if let Frame::Audio(audio) = frame {
-        let ms = audio.sample_count() as f64 / audio.sample_rate() as f64 * 1000f64;
-        duration += ms;
-        match index {
-          None => {
-            if duration >= start as f64 {
-              index = Some(decoder.position());
-            }
-          }
-          Some(start) => {
-            if duration >= end as f64 {
-              let contents = &self.bytes[start..decoder.position()];
-              write(path, contents)?;
-              return Ok(());
-            }
-          }
-        }
-      }
-      decoder.skip();
+  /// Time at the end of the last frame.
+  fn length(&self) -> Time {
+    Time::from_micros((self.frames.last().map_or(0.0, |frame| frame.end) * 1000f64) as i64)
+  }
+
+  /// Copies the frames from the one playing at `start` to the one playing at `end`, after the
+  /// `tag` and a new Xing header with the length of the clip.
+  fn slice<P: AsRef<Path>>(&self, path: P, start: &Time, end: &Time, tag: &[u8]) -> Result<()> {
+    let (start, end) = (start.micros() as f64 / 1000f64, end.micros() as f64 / 1000f64);
+    let first = self.frames.partition_point(|frame| frame.end < start);
+    if first == self.frames.len() {
+      return Err(format!("clip at {} starts after the last frame at {}", Time::from_millis(start as i64),
+                         self.length()).into());
     }
+    let last = self.frames.partition_point(|frame| frame.end < end).min(self.frames.len() - 1);
+    let frames = &self.frames[first.saturating_sub(RESERVOIR_FRAMES)..=last];
+    let (from, to) = (frames[0].position, frames[frames.len() - 1].position + frames[frames.len() - 1].length);
+    let audio = &self.bytes[from..to];
+    let mut contents = tag.to_vec();
+    contents.extend(info_frame(&audio[..4], frames, audio.len()).unwrap_or_default());
+    contents.extend_from_slice(audio);
+    write(path, contents)?;
     Ok(())
   }
 }
 
+/// Audio frames in the file, leaving out ID3 tags and the Xing header of the whole file.
+fn audio_frames(bytes: &[u8]) -> Vec<AudioFrame> {
+  let mut decoder = Decoder::new(bytes);
+  let mut frames = vec![];
+  let mut duration: f64 = 0.0;
+  while let Some(frame) = decoder.peek() {
+    if let Frame::Audio(audio) = frame {
+      let source = audio.source();
+      if !is_info_frame(source) {
+        duration += audio.sample_count() as f64 / audio.sample_rate() as f64 * 1000f64;
+        frames.push(AudioFrame {
+          position: source.as_ptr() as usize - bytes.as_ptr() as usize,
+          length: source.len(),
+          end: duration,
+          bitrate: audio.bitrate(),
+          sample_rate: audio.sample_rate(),
+          samples: audio.sample_count(),
+        });
+      }
+    }
+    decoder.skip();
+  }
+  frames
+}
+
+/// The Xing tag follows the side information, which depends on the MPEG version and channels.
+fn xing_offset(header: &[u8]) -> usize {
+  let mpeg1 = (header[1] >> 3) & 3 == 3;
+  let mono = header[3] >> 6 == 3;
+  match (mpeg1, mono) {
+    (true, false) => 36,
+    (true, true) | (false, false) => 21,
+    (false, true) => 13,
+  }
+}
+
+fn is_info_frame(frame: &[u8]) -> bool {
+  let offset = xing_offset(frame);
+  matches!(frame.get(offset..offset + 4), Some(b"Xing") | Some(b"Info")) || frame.get(36..40) == Some(&b"VBRI"[..])
+}
+
+/// A silent frame with a Xing header, or Info at a constant bitrate, counting the frames and bytes
+/// so that players show the length of the clip instead of guessing it. It copies the `header` of
+/// the first frame without CRC or padding, and is left out when that frame is too short for it.
+fn info_frame(header: &[u8], frames: &[AudioFrame], bytes: usize) -> Option<Vec<u8>> {
+  let first = &frames[0];
+  let length = first.samples / 8 * first.bitrate as usize * 1000 / first.sample_rate as usize;
+  let offset = xing_offset(header);
+  if length < offset + 16 {
+    return None;
+  }
+  let mut frame = vec![0u8; length];
+  frame[..4].copy_from_slice(&header[..4]);
+  frame[1] |= 0x01;
+  frame[2] &= !0x02;
+  let constant = frames.iter().all(|frame| frame.bitrate == first.bitrate);
+  frame[offset..offset + 4].copy_from_slice(if constant { b"Info" } else { b"Xing" });
+  frame[offset + 4..offset + 8].copy_from_slice(&3u32.to_be_bytes());
+  frame[offset + 8..offset + 12].copy_from_slice(&(frames.len() as u32).to_be_bytes());
+  frame[offset + 12..offset + 16].copy_from_slice(&((length + bytes) as u32).to_be_bytes());
+  Some(frame)
+}
+
 pub enum AudioSuffix {
   None,
   EndTime,
 }
 
-pub fn extract_sound_clips(audio_file: &str, folder: &str, prefix: &str, subtitles: &Vec<Dialogue>, suffix: AudioSuffix, sample: Option<u32>) -> Result<()> {
+impl AudioSuffix {
+  fn stem(&self, prefix: &str, dialogue: &Dialogue) -> String {
+    match self {
+      AudioSuffix::None => format!("{}_{}", prefix, dialogue.start),
+      AudioSuffix::EndTime => format!("{}_{}-{}", prefix, dialogue.start, dialogue.end)
+    }
+  }
+
+  /// Clip file name without the folder, as in the sound field of the cards.
+  pub(crate) fn name(&self, prefix: &str, dialogue: &Dialogue, extension: &str) -> String {
+    format!("{}.{}", self.stem(prefix, dialogue), extension)
+  }
+
+  /// File name of the slowed down clip.
+  pub(crate) fn slow_name(&self, prefix: &str, dialogue: &Dialogue, extension: &str) -> String {
+    format!("{}_slow.{}", self.stem(prefix, dialogue), extension)
+  }
+
+  /// File name of the plot of the clip, without the extension of the image.
+  pub(crate) fn plot_name(&self, prefix: &str, dialogue: &Dialogue) -> String {
+    format!("{}_plot", self.stem(prefix, dialogue))
+  }
+
+  pub(crate) fn filename(&self, folder: &str, prefix: &str, dialogue: &Dialogue, extension: &str) -> String {
+    format!("{}/{}", folder, self.name(prefix, dialogue, extension))
+  }
+}
+
+/// Time added around each audio clip, as subtitles often appear after speech starts.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AudioPadding {
+  /// Included before the dialogue starts
+  pub start: Time,
+  /// Included after the dialogue ends
+  pub end: Time,
+  /// Stop at the end of the previous and the start of the next dialogue
+  pub clamp: bool,
+}
+
+impl AudioPadding {
+  /// Start and end of the clip of the dialogue at `index`.
+  pub(crate) fn range(&self, subtitles: &[Dialogue], index: usize) -> (Time, Time) {
+    let dialogue = &subtitles[index];
+    let mut start = dialogue.start - self.start;
+    let mut end = dialogue.end + self.end;
+    if self.clamp {
+      if let Some(previous) = index.checked_sub(1).map(|previous| &subtitles[previous]) {
+        start = start.max(previous.end.min(dialogue.start));
+      }
+      if let Some(next) = subtitles.get(index + 1) {
+        end = end.min(next.start.max(dialogue.end));
+      }
+    }
+    (start.max(Time::default()), end)
+  }
+}
+
+/// Copies the MP3 frames of each dialogue, tagged in front of the first frame.
+#[allow(clippy::too_many_arguments)]
+pub fn extract_sound_clips(audio_file: &str, folder: &str, prefix: &str, subtitles: &[Dialogue], suffix: AudioSuffix,
+                           sample: Option<u32>, padding: &AudioPadding, tags: &TagSource) -> Result<()> {
   info!("Extracting audio clips from {}", audio_file);
   let (start, end) = sample_range(&sample);
   let mp3 = Mp3::new(audio_file)?;
-  for dialogue in subtitles {
-    if dialogue.start.milliseconds() < start || dialogue.start.milliseconds() > end {
+  for (index, dialogue) in subtitles.iter().enumerate() {
+    if dialogue.start < start || dialogue.start > end {
       continue;
     }
-    let audio_file = match suffix {
-      AudioSuffix::None => format!("{}/{}_{}.mp3", folder, prefix, dialogue.start),
-      AudioSuffix::EndTime => format!("{}/{}_{}-{}.mp3", folder, prefix, dialogue.start, dialogue.end)
-    };
+    let audio_file = suffix.filename(folder, prefix, dialogue, "mp3");
     debug!("Saving {}", audio_file);
-    mp3.slice(&audio_file, dialogue.start.milliseconds(), dialogue.end.milliseconds())?;
+    let (from, to) = padding.range(subtitles, index);
+    if from >= mp3.length() {
+      warn!("Skipping dialogue {} at {}, which starts after the end of the audio: {}", index + 1, dialogue.start,
+            dialogue.text);
+      continue;
+    }
+    let tag = tags.tags(folder, prefix, subtitles, index).id3();
+    mp3.slice(&audio_file, &from, &to, &tag)?;
   }
   info!("Done!");
   Ok(())
@@ -76,8 +217,9 @@ mod tests {
 
   use rmp3::{Decoder, Frame};
 
-  use crate::{offset_subtitle_file, Time};
-  use crate::mp3::{AudioSuffix, extract_sound_clips, Mp3};
+  use crate::{Dialogue, offset_subtitle_file, Time};
+  use crate::mp3::{AudioFrame, AudioPadding, AudioSuffix, extract_sound_clips, info_frame, is_info_frame, Mp3};
+  use crate::tags::TagSource;
 
   #[test]
   fn it_uses_rmp3() {
@@ -103,40 +245,86 @@ mod tests {
     assert_eq!(60506, frame_count);
   }
 
+  #[test]
+  fn it_writes_info_frame() {
+    let frame = |bitrate: u32| AudioFrame { position: 0, length: 0, end: 0.0, bitrate, sample_rate: 44100, samples: 1152 };
+    let header = [0xFF, 0xFA, 0x92, 0x00];
+    let info = info_frame(&header, &[frame(128), frame(128), frame(128)], 1000).unwrap();
+    assert_eq!(417, info.len());
+    assert_eq!([0xFF, 0xFB, 0x90, 0x00], info[..4]);
+    assert_eq!(b"Info", &info[36..40]);
+    assert_eq!([0, 0, 0, 3, 0, 0, 0, 3, 0, 0, 0x05, 0x89], info[40..52]);
+    assert!(is_info_frame(&info));
+
+    let info = info_frame(&header, &[frame(128), frame(192)], 1000).unwrap();
+    assert_eq!(b"Xing", &info[36..40]);
+    let mono = [0xFF, 0xF3, 0x14, 0xC0];
+    let short = AudioFrame { position: 0, length: 0, end: 0.0, bitrate: 8, sample_rate: 24000, samples: 576 };
+    assert!(info_frame(&mono, &[short], 1000).is_none());
+  }
+
+  #[test]
+  fn it_pads_up_to_neighbours() {
+    let dialogue = |start: u32, end: u32| Dialogue {
+      start: Time::new(0, 0, start, 0), end: Time::new(0, 0, end, 0), text: String::new(), image: None
+    };
+    let subtitles = vec![dialogue(0, 2), dialogue(3, 5), dialogue(5, 8), dialogue(9, 10)];
+    let padding = AudioPadding { start: Time::from_millis(500), end: Time::from_millis(1500), clamp: false };
+    assert_eq!((Time::default(), Time::new(0, 0, 3, 500)), padding.range(&subtitles, 0));
+    assert_eq!((Time::new(0, 0, 2, 500), Time::new(0, 0, 6, 500)), padding.range(&subtitles, 1));
+    let padding = AudioPadding { clamp: true, ..padding };
+    assert_eq!((Time::default(), Time::new(0, 0, 3, 0)), padding.range(&subtitles, 0));
+    assert_eq!((Time::new(0, 0, 2, 500), Time::new(0, 0, 5, 0)), padding.range(&subtitles, 1));
+    assert_eq!((Time::new(0, 0, 5, 0), Time::new(0, 0, 9, 0)), padding.range(&subtitles, 2));
+    assert_eq!((Time::new(0, 0, 8, 500), Time::new(0, 0, 11, 500)), padding.range(&subtitles, 3));
+  }
+
   #[test]
   fn it_slices_mp3() {
     let mp3 = Mp3::new("ichigo-01.mp3").unwrap();
-    let start = Time { hour: 0, min: 1, sec: 39, mil: 620 };
-    let end = Time { hour: 0, min: 1, sec: 41, mil: 620 };
-    mp3.slice("target/ichigo-1.mp3", start.milliseconds(), end.milliseconds()).unwrap();
+    let start = Time::new(0, 1, 39, 620);
+    let end = Time::new(0, 1, 41, 620);
+    mp3.slice("target/ichigo-1.mp3", &start, &end, &[]).unwrap();
+  }
+
+  #[test]
+  fn it_rejects_clips_after_the_end() {
+    let frame = AudioFrame { position: 0, length: 0, end: 1000.0, bitrate: 128, sample_rate: 44100, samples: 1152 };
+    let mp3 = Mp3 { bytes: vec![], frames: vec![frame] };
+    assert_eq!(Time::new(0, 0, 1, 0), mp3.length());
+    let start = Time::new(0, 0, 2, 0);
+    assert!(mp3.slice("target/after-the-end.mp3", &start, &Time::new(0, 0, 3, 0), &[]).is_err());
   }
 
   #[test]
   fn it_slices_ichigo() {
-    let subtitles = offset_subtitle_file("tests/ichigo-01_jp.ass", None).unwrap();
-    extract_sound_clips("ichigo-01.mp3", "target", "ichigo-01", &subtitles, AudioSuffix::EndTime, None).unwrap();
+    let subtitles = offset_subtitle_file("tests/ichigo-01_jp.ass", &None).unwrap();
+    extract_sound_clips("ichigo-01.mp3", "target", "ichigo-01", &subtitles, AudioSuffix::EndTime, None,
+                        &AudioPadding::default(), &TagSource::default()).unwrap();
   }
 
   #[test]
   fn it_extracts_totoro() {
-    let subtitles = offset_subtitle_file("tests/totoro.ja.srt", None).unwrap();
-    extract_sound_clips("totoro.mp3", "target", "totoro", &subtitles, AudioSuffix::EndTime, None).unwrap();
+    let subtitles = offset_subtitle_file("tests/totoro.ja.srt", &None).unwrap();
+    extract_sound_clips("totoro.mp3", "target", "totoro", &subtitles, AudioSuffix::EndTime, None,
+                        &AudioPadding::default(), &TagSource::default()).unwrap();
   }
 
   #[test]
   fn it_slices_totoro() {
     let mp3 = Mp3::new("totoro.mp3").unwrap();
     // 00:04:52,470 --> 00:04:54,490
-    let start = Time { hour: 0, min: 4, sec: 52, mil: 470 };
-    let end = Time { hour: 0, min: 4, sec: 54, mil: 490 };
-    mp3.slice("target/output-2.mp3", start.milliseconds(), end.milliseconds()).unwrap();
+    let start = Time::new(0, 4, 52, 470);
+    let end = Time::new(0, 4, 54, 490);
+    mp3.slice("target/output-2.mp3", &start, &end, &[]).unwrap();
   }
 
   #[test]
   fn it_extracts_one_totoro_dialogue() {
-    let mut subtitles = offset_subtitle_file("tests/totoro.ja.srt", None).unwrap();
+    let mut subtitles = offset_subtitle_file("tests/totoro.ja.srt", &None).unwrap();
     let one = subtitles.remove(50);
     let dialogue = vec![one];
-    extract_sound_clips("totoro.mp3", "target", "totoro", &dialogue, AudioSuffix::EndTime, None).unwrap();
+    extract_sound_clips("totoro.mp3", "target", "totoro", &dialogue, AudioSuffix::EndTime, None,
+                        &AudioPadding::default(), &TagSource::default()).unwrap();
   }
 }
\ No newline at end of file
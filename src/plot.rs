@@ -0,0 +1,273 @@
+use clap::ValueEnum;
+
+use crate::image::{ImageEncoder, ImageFormat};
+use crate::Result;
+use crate::spectrum::{FRAME, HOP, Spectra};
+
+/// Lowest and highest fundamental frequency of speech, and the axis of the pitch over a waveform.
+const PITCH_RANGE: (f32, f32) = (60.0, 500.0);
+
+/// Frequency axis of the spectrogram, where the pitch is drawn too.
+const SPECTRUM_RANGE: (f32, f32) = (50.0, 8000.0);
+
+/// Decibels below the loudest bin that are still drawn in the spectrogram.
+const DYNAMIC_RANGE: f32 = 80.0;
+
+/// Seconds between pitch estimates.
+const PITCH_STEP: f32 = 0.01;
+
+/// Pitch is estimated at a quarter of the rate, which is plenty for the fundamental.
+const DECIMATION: usize = 4;
+
+/// Lower values of the normalized difference mean a clearer period, as in YIN.
+const PERIODICITY: f32 = 0.15;
+
+/// Frames quieter than this are not voiced.
+const SILENCE: f32 = 0.001;
+
+const BACKGROUND: [u8; 3] = [0xff, 0xff, 0xff];
+const WAVE: [u8; 3] = [0x33, 0x66, 0x99];
+const PITCH: [u8; 3] = [0xdd, 0x33, 0x22];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum PlotKind {
+  #[default]
+  Waveform,
+  /// Frequencies over time on a logarithmic axis
+  Spectrogram,
+}
+
+/// Image of each audio clip for pitch accent and shadowing practice.
+#[derive(Clone, Copy, Debug)]
+pub struct PlotOptions {
+  pub kind: PlotKind,
+  pub width: u32,
+  pub height: u32,
+  /// Draw the fundamental frequency over the plot
+  pub pitch: bool,
+}
+
+impl Default for PlotOptions {
+  fn default() -> Self {
+    PlotOptions { kind: PlotKind::Waveform, width: 600, height: 160, pitch: false }
+  }
+}
+
+impl PlotOptions {
+  /// Plots stereo samples at `rate` to a PNG, with the extension appended to `filename`.
+  pub(crate) fn save(&self, filename: &str, samples: &[(f32, f32)], rate: u32) -> Result<()> {
+    let canvas = self.render(samples, rate);
+    let encoder = ImageEncoder { format: ImageFormat::Png, ..ImageEncoder::default() };
+    encoder.save_rgb(filename, &canvas.pixels, canvas.width, canvas.height)
+  }
+
+  /// Plot of at least one pixel, however small the size.
+  fn render(&self, samples: &[(f32, f32)], rate: u32) -> Canvas {
+    let mut canvas = Canvas::new(self.width.max(1), self.height.max(1));
+    if samples.is_empty() {
+      return canvas;
+    }
+    let axis = match self.kind {
+      PlotKind::Waveform => {
+        waveform(&mut canvas, samples);
+        PITCH_RANGE
+      }
+      PlotKind::Spectrogram => {
+        spectrogram(&mut canvas, samples, rate);
+        SPECTRUM_RANGE
+      }
+    };
+    if self.pitch {
+      contour(&mut canvas, &pitch_contour(samples, rate), samples.len() as f32 / rate as f32, axis);
+    }
+    canvas
+  }
+}
+
+/// Packed RGB pixels, with the origin at the top left.
+struct Canvas {
+  pixels: Vec<u8>,
+  width: u32,
+  height: u32,
+}
+
+impl Canvas {
+  fn new(width: u32, height: u32) -> Canvas {
+    Canvas { pixels: BACKGROUND.repeat((width * height) as usize), width, height }
+  }
+
+  fn set(&mut self, x: u32, y: u32, color: [u8; 3]) {
+    if x < self.width && y < self.height {
+      let offset = 3 * (y * self.width + x) as usize;
+      self.pixels[offset..offset + 3].copy_from_slice(&color);
+    }
+  }
+
+  /// Vertical line between two rows, in either order.
+  fn line(&mut self, x: u32, from: u32, to: u32, color: [u8; 3]) {
+    for y in from.min(to)..=from.max(to) {
+      self.set(x, y, color);
+    }
+  }
+}
+
+/// Range of the samples of each column, scaled to the loudest sample of the clip.
+fn waveform(canvas: &mut Canvas, samples: &[(f32, f32)]) {
+  let mono = samples.iter().map(|(l, r)| (l + r) / 2.0).collect::<Vec<_>>();
+  let peak = mono.iter().fold(f32::EPSILON, |peak, sample| peak.max(sample.abs()));
+  let middle = (canvas.height - 1) as f32 / 2.0;
+  let row = |sample: f32| (middle - sample / peak * middle) as u32;
+  for x in 0..canvas.width {
+    let start = x as usize * mono.len() / canvas.width as usize;
+    let end = ((x + 1) as usize * mono.len() / canvas.width as usize).max(start + 1).min(mono.len());
+    let Some(column) = mono.get(start..end) else { continue; };
+    let (low, high) = column.iter().fold((0f32, 0f32), |(low, high), &sample| (low.min(sample), high.max(sample)));
+    canvas.line(x, row(high), row(low), WAVE);
+  }
+}
+
+/// Loudness of each frequency over time, darker for louder.
+fn spectrogram(canvas: &mut Canvas, samples: &[(f32, f32)], rate: u32) {
+  let spectra = Spectra::new();
+  let frames = (0..samples.len())
+    .step_by(HOP)
+    .map(|center| spectra.magnitudes(samples, center as isize - FRAME as isize / 2))
+    .map(|frame| frame.iter().map(|magnitude| 20.0 * (magnitude + 1e-9).log10()).collect::<Vec<_>>())
+    .collect::<Vec<_>>();
+  let loudest = frames.iter().flatten().fold(f32::MIN, |loudest, &level| loudest.max(level));
+  let bins = (0..canvas.height)
+    .map(|y| frequency(canvas.height - 1 - y, canvas.height, SPECTRUM_RANGE))
+    .map(|frequency| ((frequency * FRAME as f32 / rate as f32).round() as usize).min(FRAME / 2 - 1))
+    .collect::<Vec<_>>();
+  for x in 0..canvas.width {
+    let frame = &frames[(x as usize * frames.len() / canvas.width as usize).min(frames.len() - 1)];
+    for (y, bin) in bins.iter().enumerate() {
+      let level = ((frame[*bin] - loudest + DYNAMIC_RANGE) / DYNAMIC_RANGE).clamp(0.0, 1.0);
+      let shade = (255.0 * (1.0 - level)) as u8;
+      canvas.set(x, y as u32, [shade, shade, shade]);
+    }
+  }
+}
+
+/// Pitch contour over the whole width, joining the columns of the same voiced stretch.
+fn contour(canvas: &mut Canvas, pitch: &[Option<f32>], seconds: f32, axis: (f32, f32)) {
+  let mut previous = None;
+  for x in 0..canvas.width {
+    let time = (x as f32 + 0.5) / canvas.width as f32 * seconds;
+    let current = pitch.get((time / PITCH_STEP) as usize).copied().flatten()
+      .map(|frequency| canvas.height - 1 - row(frequency, canvas.height, axis));
+    if let Some(y) = current {
+      canvas.line(x, previous.unwrap_or(y), y, PITCH);
+      canvas.line(x, y.saturating_sub(1), y + 1, PITCH);
+    }
+    previous = current;
+  }
+}
+
+/// Frequency at `row` counted from the bottom, on a logarithmic axis.
+fn frequency(row: u32, height: u32, (low, high): (f32, f32)) -> f32 {
+  low * (high / low).powf(row as f32 / (height.max(2) - 1) as f32)
+}
+
+/// Row of `frequency` counted from the bottom, on a logarithmic axis.
+fn row(frequency: f32, height: u32, (low, high): (f32, f32)) -> u32 {
+  let fraction = (frequency / low).ln() / (high / low).ln();
+  (fraction.clamp(0.0, 1.0) * (height.max(2) - 1) as f32).round() as u32
+}
+
+/// Fundamental frequency every `PITCH_STEP`, or None where the audio is unvoiced.
+pub(crate) fn pitch_contour(samples: &[(f32, f32)], rate: u32) -> Vec<Option<f32>> {
+  let mono = samples.chunks(DECIMATION)
+    .map(|chunk| chunk.iter().map(|(l, r)| l + r).sum::<f32>() / (2 * chunk.len()) as f32)
+    .collect::<Vec<_>>();
+  let rate = rate as f32 / DECIMATION as f32;
+  let lags = ((rate / PITCH_RANGE.1) as usize, (rate / PITCH_RANGE.0).ceil() as usize);
+  let step = (rate * PITCH_STEP) as usize;
+  (0..mono.len().div_ceil(step))
+    .map(|i| {
+      // Each estimate compares a window centered on its time with the window a period later
+      let start = (i * step).checked_sub(lags.1)?;
+      fundamental(mono.get(start..start + 2 * lags.1)?, lags, rate)
+    })
+    .collect()
+}
+
+/// Frequency of the shortest lag at which the frame clearly repeats, by the cumulative mean
+/// normalized difference of YIN.
+fn fundamental(frame: &[f32], (min_lag, max_lag): (usize, usize), rate: f32) -> Option<f32> {
+  let window = frame.len() - max_lag;
+  let energy = frame[..window].iter().map(|x| x * x).sum::<f32>() / window as f32;
+  if energy.sqrt() < SILENCE {
+    return None;
+  }
+  let mut sum = 0.0;
+  let normalized = (0..=max_lag)
+    .map(|lag| {
+      let difference = (0..window).map(|j| (frame[j] - frame[j + lag]).powi(2)).sum::<f32>();
+      sum += difference;
+      if lag == 0 || sum <= 0.0 { 1.0 } else { difference * lag as f32 / sum }
+    })
+    .collect::<Vec<_>>();
+  let mut lag = (min_lag.max(1)..max_lag).find(|&lag| normalized[lag] < PERIODICITY)?;
+  while lag + 1 < max_lag && normalized[lag + 1] < normalized[lag] {
+    lag += 1;
+  }
+  // The minimum between samples, from the parabola through the lag and its neighbours
+  let (before, at, after) = (normalized[lag - 1], normalized[lag], normalized[lag + 1]);
+  let curvature = before - 2.0 * at + after;
+  let shift = if curvature > f32::EPSILON { 0.5 * (before - after) / curvature } else { 0.0 };
+  Some(rate / (lag as f32 + shift))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::f32::consts::PI;
+
+  use super::*;
+
+  const RATE: u32 = 48000;
+
+  fn tone(frequency: f32, samples: usize) -> Vec<(f32, f32)> {
+    (0..samples)
+      .map(|i| 0.5 * (2.0 * PI * frequency * i as f32 / RATE as f32).sin())
+      .map(|sample| (sample, sample))
+      .collect()
+  }
+
+  #[test]
+  fn it_tracks_pitch() {
+    let mut samples = tone(220.0, RATE as usize);
+    samples.extend(vec![(0.0, 0.0); RATE as usize / 2]);
+    let contour = pitch_contour(&samples, RATE);
+    assert_eq!(150, contour.len());
+    for frequency in contour[10..90].iter() {
+      let frequency = frequency.unwrap();
+      assert!((frequency - 220.0).abs() < 2.0, "{}", frequency);
+    }
+    assert!(contour[110..].iter().all(Option::is_none));
+  }
+
+  #[test]
+  fn it_maps_frequencies_to_rows() {
+    assert_eq!(0, row(60.0, 101, PITCH_RANGE));
+    assert_eq!(100, row(500.0, 101, PITCH_RANGE));
+    assert_eq!(100, row(1000.0, 101, PITCH_RANGE));
+    assert_eq!(50, row(frequency(50, 101, SPECTRUM_RANGE), 101, SPECTRUM_RANGE));
+  }
+
+  #[test]
+  fn it_plots_each_kind() {
+    let samples = tone(220.0, RATE as usize / 2);
+    for kind in [PlotKind::Waveform, PlotKind::Spectrogram] {
+      let options = PlotOptions { kind, width: 200, height: 100, pitch: true };
+      let pixels = options.render(&samples, RATE).pixels;
+      assert_eq!(3 * 200 * 100, pixels.len());
+      assert!(pixels.chunks(3).any(|pixel| pixel == PITCH));
+      assert!(pixels.chunks(3).any(|pixel| pixel == BACKGROUND));
+    }
+    let blank = PlotOptions::default().render(&[], RATE).pixels;
+    assert!(blank.chunks(3).all(|pixel| pixel == BACKGROUND));
+    let empty = PlotOptions { width: 0, height: 0, ..PlotOptions::default() }.render(&samples, RATE);
+    assert_eq!((1, 1, 3), (empty.width, empty.height, empty.pixels.len()));
+  }
+}
@@ -0,0 +1,140 @@
+use std::f32::consts::PI;
+use std::ops::{Add, Mul, Sub};
+
+/// Samples in each transform, about 43 ms at 48 kHz.
+pub(crate) const FRAME: usize = 2048;
+
+/// Frames overlap by half, which the square root of a Hann window adds back up to the original.
+pub(crate) const HOP: usize = FRAME / 2;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Complex {
+  pub re: f32,
+  im: f32,
+}
+
+impl Complex {
+  fn new(re: f32, im: f32) -> Complex {
+    Complex { re, im }
+  }
+
+  pub fn norm(&self) -> f32 {
+    self.re.hypot(self.im)
+  }
+
+  pub fn conj(&self) -> Complex {
+    Complex::new(self.re, -self.im)
+  }
+
+  pub fn scale(&self, factor: f32) -> Complex {
+    Complex::new(self.re * factor, self.im * factor)
+  }
+}
+
+impl Add for Complex {
+  type Output = Complex;
+
+  fn add(self, other: Complex) -> Complex {
+    Complex::new(self.re + other.re, self.im + other.im)
+  }
+}
+
+impl Sub for Complex {
+  type Output = Complex;
+
+  fn sub(self, other: Complex) -> Complex {
+    Complex::new(self.re - other.re, self.im - other.im)
+  }
+}
+
+impl Mul for Complex {
+  type Output = Complex;
+
+  fn mul(self, other: Complex) -> Complex {
+    Complex::new(self.re * other.re - self.im * other.im, self.re * other.im + self.im * other.re)
+  }
+}
+
+/// Short-time Fourier transform of stereo audio in overlapping frames, and back.
+pub(crate) struct Spectra {
+  window: Vec<f32>,
+  /// Roots of unity for the forward transform
+  twiddles: Vec<Complex>,
+}
+
+impl Spectra {
+  pub fn new() -> Spectra {
+    let window = (0..FRAME)
+      .map(|i| (0.5 - 0.5 * (2.0 * PI * i as f32 / FRAME as f32).cos()).sqrt())
+      .collect();
+    let twiddles = (0..FRAME / 2)
+      .map(|k| -2.0 * PI * k as f32 / FRAME as f32)
+      .map(|angle| Complex::new(angle.cos(), angle.sin()))
+      .collect();
+    Spectra { window, twiddles }
+  }
+
+  /// Windowed spectra of the left and right channels of the frame at `start`, which may reach past
+  /// either end.
+  pub fn analyse(&self, samples: &[(f32, f32)], start: isize) -> (Vec<Complex>, Vec<Complex>) {
+    let mut left = vec![Complex::default(); FRAME];
+    let mut right = vec![Complex::default(); FRAME];
+    for (i, weight) in self.window.iter().enumerate() {
+      let Some(&(l, r)) = usize::try_from(start + i as isize).ok().and_then(|i| samples.get(i)) else { continue; };
+      left[i] = Complex::new(l * weight, 0.0);
+      right[i] = Complex::new(r * weight, 0.0);
+    }
+    self.transform(&mut left, false);
+    self.transform(&mut right, false);
+    (left, right)
+  }
+
+  /// Magnitudes of the frame at `start` up to half the rate, averaged over both channels.
+  pub fn magnitudes(&self, samples: &[(f32, f32)], start: isize) -> Vec<f32> {
+    let (left, right) = self.analyse(samples, start);
+    left.iter().zip(&right).take(FRAME / 2).map(|(l, r)| (l.norm() + r.norm()) / 2.0).collect()
+  }
+
+  /// Windowed samples of a spectrum, to add to the overlapping frames.
+  pub fn synthesize(&self, mut spectrum: Vec<Complex>) -> Vec<f32> {
+    self.transform(&mut spectrum, true);
+    spectrum.iter().zip(&self.window).map(|(x, weight)| x.re * weight).collect()
+  }
+
+  /// Radix-2 fast Fourier transform in place, scaled on the way back.
+  fn transform(&self, buffer: &mut [Complex], inverse: bool) {
+    let n = buffer.len();
+    let mut j = 0;
+    for i in 1..n {
+      let mut bit = n >> 1;
+      while j & bit != 0 {
+        j ^= bit;
+        bit >>= 1;
+      }
+      j |= bit;
+      if i < j {
+        buffer.swap(i, j);
+      }
+    }
+    let mut length = 2;
+    while length <= n {
+      let stride = n / length;
+      for start in (0..n).step_by(length) {
+        for k in 0..length / 2 {
+          let twiddle = self.twiddles[k * stride];
+          let twiddle = if inverse { twiddle.conj() } else { twiddle };
+          let even = buffer[start + k];
+          let odd = buffer[start + k + length / 2] * twiddle;
+          buffer[start + k] = even + odd;
+          buffer[start + k + length / 2] = even - odd;
+        }
+      }
+      length <<= 1;
+    }
+    if inverse {
+      for x in buffer.iter_mut() {
+        *x = x.scale(1.0 / n as f32);
+      }
+    }
+  }
+}
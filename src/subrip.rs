@@ -2,7 +2,7 @@ use pest::iterators::Pair;
 use pest::Parser;
 use pest_derive::Parser;
 
-use crate::{Dialogue, Time};
+use crate::{Dialogue, plain_text, Time};
 
 #[derive(Parser)]
 #[grammar = "subrip.pest"]
@@ -11,11 +11,11 @@ pub struct SubripParser;
 impl From<Pair<'_, Rule>> for Time {
   fn from(value: Pair<Rule>) -> Self {
     let mut time = value.into_inner();
-    let hour: u8 = time.next().unwrap().as_str().parse().unwrap();
-    let min: u8 = time.next().unwrap().as_str().parse().unwrap();
-    let sec: u8 = time.next().unwrap().as_str().parse().unwrap();
-    let mil: u16 = time.next().unwrap().as_str().parse().unwrap();
-    Time { hour, min, sec, mil }
+    let hour: u32 = time.next().unwrap().as_str().parse().unwrap();
+    let min: u32 = time.next().unwrap().as_str().parse().unwrap();
+    let sec: u32 = time.next().unwrap().as_str().parse().unwrap();
+    let mil: u32 = time.next().unwrap().as_str().parse().unwrap();
+    Time::new(hour, min, sec, mil)
   }
 }
 
@@ -38,7 +38,7 @@ fn subrip_to_dialogue(pair: Pair<Rule>, mut list: Vec<Dialogue>) -> Vec<Dialogue
         let payload = inner.next().unwrap();
         let text = payload.into_inner().next().unwrap().as_str()
           .to_string().replace('\n', "<br/>");
-        let dialogue = Dialogue { start, end, text };
+        let dialogue = Dialogue { start, end, text, image: None };
         list.push(dialogue);
       }
       _ => {
@@ -49,6 +49,20 @@ fn subrip_to_dialogue(pair: Pair<Rule>, mut list: Vec<Dialogue>) -> Vec<Dialogue
   list
 }
 
+pub fn dialogue_to_subrip(dialogue: &[Dialogue]) -> String {
+  dialogue.iter()
+    .filter(|d| !d.text.is_empty())
+    .enumerate()
+    .map(|(index, d)| format!("{}\n{} --> {}\n{}\n\n", index + 1, timestamp(&d.start), timestamp(&d.end),
+                              plain_text(&d.text)))
+    .collect()
+}
+
+fn timestamp(time: &Time) -> String {
+  let (hour, min, sec, mil) = time.parts();
+  format!("{:02}:{:02}:{:02},{:03}", hour, min, sec, mil)
+}
+
 #[cfg(test)]
 mod tests {
   use std::fs;
@@ -66,4 +80,13 @@ mod tests {
     let dialogue = subtitles.remove(50);
     assert_matches!(dialogue, Dialogue {text, .. } if text == "早く～！");
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn it_writes_subrip() {
+    let contents = fs::read_to_string("tests/totoro.ja.srt").unwrap();
+    let subtitles = parse_subrip_to_dialogue(&contents).unwrap();
+    let written = dialogue_to_subrip(&subtitles);
+    assert!(written.starts_with("1\n00:00:11,040 --> 00:00:15,060\n"));
+    assert_eq!(subtitles, parse_subrip_to_dialogue(&written).unwrap());
+  }
+}
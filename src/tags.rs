@@ -0,0 +1,208 @@
+use std::fs;
+
+use log::debug;
+
+use crate::{Dialogue, find_secondary_matches, plain_text, single_line};
+use crate::image::ImageFormat;
+
+/// Picture type of the front cover, in ID3 and in FLAC picture blocks.
+const FRONT_COVER: u8 = 3;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Where the tags of the clips come from, besides the dialogue itself.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TagSource<'a> {
+  /// Translation of the dialogue, for the comment
+  pub translation: &'a [Dialogue],
+  /// Format of the screenshots in the output folder to embed as cover art
+  pub cover: Option<ImageFormat>,
+}
+
+/// Metadata of a clip, so it can be played outside of Anki too.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ClipTags {
+  /// Dialogue text
+  pub title: String,
+  /// Translation
+  pub comment: String,
+  /// Episode
+  pub album: String,
+  /// Number of the dialogue, of all the dialogue
+  pub track: (usize, usize),
+  pub cover: Option<Cover>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Cover {
+  pub mime: &'static str,
+  pub data: Vec<u8>,
+}
+
+impl TagSource<'_> {
+  /// Tags of the dialogue at `index`, with the screenshot taken from the middle of it, or the
+  /// first of several, as the cover.
+  pub(crate) fn tags(&self, folder: &str, prefix: &str, subtitles: &[Dialogue], index: usize) -> ClipTags {
+    let dialogue = &subtitles[index];
+    let comment = find_secondary_matches(dialogue, self.translation).iter()
+      .map(|d| single_line(&plain_text(&d.text)))
+      .collect::<Vec<_>>()
+      .join(" ");
+    let cover = self.cover.and_then(|format| {
+      let stem = format!("{}/{}_{}", folder, prefix, dialogue.start.hms());
+      let data = fs::read(format!("{}.{}", stem, format.extension()))
+        .or_else(|_| fs::read(format!("{}_1.{}", stem, format.extension())));
+      match data {
+        Ok(data) => Some(Cover { mime: format.mime(), data }),
+        Err(_) => {
+          debug!("No screenshot at {} for the cover", stem);
+          None
+        }
+      }
+    });
+    ClipTags {
+      title: single_line(&plain_text(&dialogue.text)),
+      comment,
+      album: prefix.to_string(),
+      track: (index + 1, subtitles.len()),
+      cover,
+    }
+  }
+}
+
+impl ClipTags {
+  pub fn track_number(&self) -> String {
+    format!("{}/{}", self.track.0, self.track.1)
+  }
+
+  /// ID3v2.4 tag with UTF-8 text, to go in front of MP3 frames.
+  pub fn id3(&self) -> Vec<u8> {
+    let mut frames = vec![];
+    id3_frame(&mut frames, b"TIT2", &text_frame(&self.title));
+    id3_frame(&mut frames, b"TALB", &text_frame(&self.album));
+    id3_frame(&mut frames, b"TRCK", &text_frame(&self.track_number()));
+    if !self.comment.is_empty() {
+      // Encoding, undetermined language, empty description
+      let mut comment = vec![3];
+      comment.extend_from_slice(b"und\0");
+      comment.extend_from_slice(self.comment.as_bytes());
+      id3_frame(&mut frames, b"COMM", &comment);
+    }
+    if let Some(cover) = &self.cover {
+      let mut picture = vec![3];
+      picture.extend_from_slice(cover.mime.as_bytes());
+      picture.extend_from_slice(&[0, FRONT_COVER, 0]);
+      picture.extend_from_slice(&cover.data);
+      id3_frame(&mut frames, b"APIC", &picture);
+    }
+    let mut tag = b"ID3\x04\x00\x00".to_vec();
+    tag.extend_from_slice(&syncsafe(frames.len()));
+    tag.extend_from_slice(&frames);
+    tag
+  }
+
+  /// Base64 FLAC picture block of the cover, which Ogg players read from the
+  /// METADATA_BLOCK_PICTURE comment.
+  pub fn picture_block(&self) -> Option<String> {
+    let cover = self.cover.as_ref()?;
+    let mut block = vec![];
+    block.extend_from_slice(&(FRONT_COVER as u32).to_be_bytes());
+    block.extend_from_slice(&(cover.mime.len() as u32).to_be_bytes());
+    block.extend_from_slice(cover.mime.as_bytes());
+    // No description, and the size and colors are left to the player
+    block.extend_from_slice(&[0; 20]);
+    block.extend_from_slice(&(cover.data.len() as u32).to_be_bytes());
+    block.extend_from_slice(&cover.data);
+    Some(base64(&block))
+  }
+}
+
+fn text_frame(text: &str) -> Vec<u8> {
+  let mut frame = vec![3];
+  frame.extend_from_slice(text.as_bytes());
+  frame
+}
+
+fn id3_frame(frames: &mut Vec<u8>, id: &[u8; 4], contents: &[u8]) {
+  frames.extend_from_slice(id);
+  frames.extend_from_slice(&syncsafe(contents.len()));
+  frames.extend_from_slice(&[0, 0]);
+  frames.extend_from_slice(contents);
+}
+
+/// ID3v2.4 sizes use 7 bits of each byte, so they never look like an MPEG frame sync.
+fn syncsafe(size: usize) -> [u8; 4] {
+  [(size >> 21 & 0x7f) as u8, (size >> 14 & 0x7f) as u8, (size >> 7 & 0x7f) as u8, (size & 0x7f) as u8]
+}
+
+fn base64(bytes: &[u8]) -> String {
+  let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+  for chunk in bytes.chunks(3) {
+    let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8 * i));
+    for i in 0..4 {
+      if i <= chunk.len() {
+        encoded.push(BASE64[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+      } else {
+        encoded.push('=');
+      }
+    }
+  }
+  encoded
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::offset_subtitle_file;
+
+  use super::*;
+
+  #[test]
+  fn it_encodes_base64() {
+    assert_eq!("", base64(b""));
+    assert_eq!("Zg==", base64(b"f"));
+    assert_eq!("Zm8=", base64(b"fo"));
+    assert_eq!("Zm9vYmFy", base64(b"foobar"));
+  }
+
+  #[test]
+  fn it_writes_id3_tag() {
+    let tags = ClipTags {
+      title: "はやく！".into(),
+      album: "totoro".into(),
+      track: (3, 120),
+      cover: Some(Cover { mime: "image/jpeg", data: vec![0xff; 200] }),
+      ..ClipTags::default()
+    };
+    let tag = tags.id3();
+    assert_eq!(b"ID3\x04\x00\x00\x00\x00\x02\x18", &tag[..10]);
+    assert_eq!(10 + 2 * 128 + 0x18, tag.len());
+    assert_eq!(b"TIT2\x00\x00\x00\x0d\x00\x00\x03", &tag[10..21]);
+    assert_eq!("はやく！", std::str::from_utf8(&tag[21..33]).unwrap());
+    assert!(!tag.windows(4).any(|id| id == b"COMM"));
+    assert!(tags.picture_block().unwrap().starts_with("AAAAAwAAAAppbWFnZS9qcGVn"));
+  }
+
+  #[test]
+  fn it_tags_dialogue_with_translation() {
+    let primary = offset_subtitle_file("tests/totoro.ja.vtt", &None).unwrap();
+    let secondary = offset_subtitle_file("tests/totoro.en.vtt", &None).unwrap();
+    let source = TagSource { translation: &secondary, cover: Some(ImageFormat::Jpeg) };
+    let index = primary.iter().position(|d| d.text.contains("はやく！")).unwrap();
+    let tags = source.tags("target", "totoro", &primary, index);
+    assert_eq!((index + 1, primary.len()), tags.track);
+    assert_eq!("totoro", tags.album);
+    assert!(tags.title.contains("はやく！"));
+    assert!(tags.comment.contains("Come on!"));
+  }
+
+  #[test]
+  fn it_strips_override_tags() {
+    let primary = offset_subtitle_file("tests/ichigo-01_jp.ass", &None).unwrap();
+    let secondary = offset_subtitle_file("tests/ichigo-01_en.ass", &None).unwrap();
+    let source = TagSource { translation: &secondary, cover: None };
+    let index = primary.iter().position(|d| d.text.contains("はいー、どうぞー")).unwrap();
+    let tags = source.tags("target", "ichigo-01", &primary, index);
+    assert_eq!("はいー、どうぞー", tags.title);
+    assert_eq!("Yes, come in.", tags.comment);
+  }
+}
@@ -2,7 +2,7 @@ use pest::iterators::Pair;
 use pest::Parser;
 use pest_derive::Parser;
 
-use crate::{Dialogue, Time};
+use crate::{Dialogue, plain_text, Time};
 
 #[derive(Parser)]
 #[grammar = "webvtt.pest"]
@@ -11,11 +11,11 @@ pub struct WebVttParser;
 impl From<Pair<'_, Rule>> for Time {
   fn from(value: Pair<Rule>) -> Self {
     let mut time = value.into_inner();
-    let hour: u8 = time.next().unwrap().as_str().parse().unwrap();
-    let min: u8 = time.next().unwrap().as_str().parse().unwrap();
-    let sec: u8 = time.next().unwrap().as_str().parse().unwrap();
-    let mil: u16 = time.next().unwrap().as_str().parse().unwrap();
-    Time { hour, min, sec, mil }
+    let hour: u32 = time.next().unwrap().as_str().parse().unwrap();
+    let min: u32 = time.next().unwrap().as_str().parse().unwrap();
+    let sec: u32 = time.next().unwrap().as_str().parse().unwrap();
+    let mil: u32 = time.next().unwrap().as_str().parse().unwrap();
+    Time::new(hour, min, sec, mil)
   }
 }
 
@@ -50,7 +50,7 @@ fn webvtt_to_dialogue(pair: Pair<Rule>, mut list: Vec<Dialogue>) -> Vec<Dialogue
         let end: Time = inner.next().unwrap().into();
         let payload = inner.next().unwrap();
         let text = payload.into_inner().next().unwrap().as_str().to_string();
-        let dialogue = Dialogue { start, end, text };
+        let dialogue = Dialogue { start, end, text, image: None };
         list.push(dialogue);
       }
       _ => {
@@ -61,6 +61,20 @@ fn webvtt_to_dialogue(pair: Pair<Rule>, mut list: Vec<Dialogue>) -> Vec<Dialogue
   list
 }
 
+pub fn dialogue_to_webvtt(dialogue: &[Dialogue]) -> String {
+  let cues: String = dialogue.iter()
+    .filter(|d| !d.text.is_empty())
+    .enumerate()
+    .map(|(index, d)| format!("{}\n{} --> {}\n{}\n\n", index + 1, timestamp(&d.start), timestamp(&d.end),
+                              plain_text(&d.text)))
+    .collect();
+  format!("WEBVTT\n\n{}", cues)
+}
+
+fn timestamp(time: &Time) -> String {
+  let (hour, min, sec, mil) = time.parts();
+  format!("{:02}:{:02}:{:02}.{:03}", hour, min, sec, mil)
+}
 
 #[cfg(test)]
 mod tests {
@@ -92,8 +106,8 @@ mod tests {
 
   #[test]
   fn it_matches_secondary_subtitle() {
-    let primary = offset_subtitle_file("tests/totoro.ja.vtt").unwrap();
-    let secondary = offset_subtitle_file("tests/totoro.en.vtt").unwrap();
+    let primary = offset_subtitle_file("tests/totoro.ja.vtt", &None).unwrap();
+    let secondary = offset_subtitle_file("tests/totoro.en.vtt", &None).unwrap();
     assert_eq!(839, primary.len());
     assert_eq!(615, secondary.len());
     let first = primary.get(26).unwrap();
@@ -104,8 +118,8 @@ mod tests {
 
   #[test]
   fn it_generates_tab_separated() {
-    let primary = offset_subtitle_file("tests/totoro.ja.vtt").unwrap();
-    let secondary = offset_subtitle_file("tests/totoro.en.vtt").unwrap();
+    let primary = offset_subtitle_file("tests/totoro.ja.vtt", &None).unwrap();
+    let secondary = offset_subtitle_file("tests/totoro.en.vtt", &None).unwrap();
     for first in primary.iter() {
       let second = find_secondary_matches(first, &secondary);
       let text = first.text
@@ -117,4 +131,13 @@ mod tests {
       println!("{}\t{}", text, second);
     }
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn it_writes_webvtt() {
+    let contents = fs::read_to_string("tests/totoro.en.vtt").unwrap();
+    let cues = parse_webvtt_to_dialogue(&contents).unwrap();
+    let written = dialogue_to_webvtt(&cues);
+    assert!(written.starts_with("WEBVTT\n\n1\n"));
+    assert_eq!(cues, parse_webvtt_to_dialogue(&written).unwrap());
+  }
+}